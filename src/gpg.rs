@@ -77,7 +77,8 @@ impl Gpg {
     }
 }
 
-fn get_gpg_port(path: PathBuf) -> Result<(String, Vec<u8>)> {
+// reads the port and nonce from one of gpg-agent's socket emulation files
+pub fn get_gpg_port(path: PathBuf) -> Result<(String, Vec<u8>)> {
     let mut f = File::open(&path)?;
     let metadata = fs::metadata(path)?;
     let mut buffer = vec![0u8; metadata.len() as usize];
@@ -87,6 +88,9 @@ fn get_gpg_port(path: PathBuf) -> Result<(String, Vec<u8>)> {
         .iter()
         .take_while(|c| **c != b'\n' && **c != b'\r')
         .count();
+    if line_size >= buffer.len() {
+        bail!("socket file is missing the nonce");
+    }
     let port = str::from_utf8(&buffer[..line_size])?.to_string();
     let nonce = buffer[(line_size + 1)..].to_vec();
     if nonce.len() != 16 {
@@ -95,3 +99,39 @@ fn get_gpg_port(path: PathBuf) -> Result<(String, Vec<u8>)> {
 
     Ok((port, nonce))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_get_gpg_port() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("S.gpg-agent");
+        let nonce = [7u8; 16];
+        fs::write(&path, [b"52423\n".as_slice(), &nonce].concat()).unwrap();
+
+        let (port, read_nonce) = get_gpg_port(path).unwrap();
+        assert_eq!("52423", port);
+        assert_eq!(nonce.to_vec(), read_nonce);
+    }
+
+    #[test]
+    fn test_get_gpg_port_bad_nonce() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("S.gpg-agent");
+
+        fs::write(&path, [b"52423\n".as_slice(), &[7u8; 15]].concat()).unwrap();
+        assert!(get_gpg_port(path.clone()).is_err());
+
+        fs::write(&path, b"52423").unwrap();
+        assert!(get_gpg_port(path.clone()).is_err());
+    }
+
+    #[test]
+    fn test_get_gpg_port_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(get_gpg_port(dir.path().join("S.gpg-agent")).is_err());
+    }
+}
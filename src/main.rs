@@ -6,11 +6,33 @@ mod ssh;
 
 use crate::gpg::Gpg;
 use crate::licenses::Licenses;
-use crate::ssh::SshPageant;
+use crate::ssh::approval::{ApprovalLayer, Approver, CommandApprover, PinentryApprover};
+use crate::ssh::audit::{AuditLayer, AuditLog};
+use crate::ssh::backend::{connect_all, AgentBackend, BackendChain};
+use crate::ssh::cache::CacheLayer;
+use crate::ssh::certificate::{Certificate, CertificateLayer};
+use crate::ssh::clock::SystemClock;
+use crate::ssh::destination::{DestinationLayer, DestinationRule};
+use crate::ssh::extension::ExtensionLayer;
+use crate::ssh::filter::{FilterLayer, IdentityFilter, KeyRule};
+use crate::ssh::keystore::KeystoreLayer;
+use crate::ssh::known_hosts::KnownHosts;
+use crate::ssh::list_keys::ListKeys;
+use crate::ssh::lock::{IdleLock, LockLayer};
+use crate::ssh::order::{OrderLayer, OrderRule};
+use crate::ssh::policy::{PolicyLayer, RequestKind};
+use crate::ssh::rate_limit::{Alerter, CommandAlerter, RateLimit, RateLimitLayer};
+use crate::ssh::server::{self, ListenAddress};
+use crate::ssh::session::Session;
+use crate::ssh::sign::Sign;
+use crate::ssh::verify::Verify;
 use anyhow::{anyhow, Result};
-use clap::Parser;
+use clap::{ArgGroup, Parser};
 use flexi_logger::{FileSpec, Logger, WriteMode};
 use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[clap(
@@ -25,32 +47,400 @@ struct Opts {
 #[derive(Parser)]
 enum SubCommand {
     Gpg(Gpg),
-    Ssh(Ssh),
+    Ssh(Box<Ssh>),
     Licenses(Licenses),
 }
 
-#[derive(Parser)]
-pub struct Ssh {}
+#[derive(Parser, Clone)]
+#[clap(group(ArgGroup::new("rate_limit").multiple(true)))]
+pub struct Ssh {
+    // without a command, requests are relayed on stdin and stdout or to `--listen`
+    #[clap(subcommand)]
+    command: Option<SshCommand>,
+
+    /// Where to relay requests to: `pageant`, `gpg[:<path to S.gpg-agent.ssh>]`,
+    /// `pipe[:<named pipe>]` or `unix:<path to agent socket>`.
+    /// Separate backends with commas to fail over between them in order,
+    /// and repeat it to merge the keys of several agents.
+    #[clap(long = "backend", value_name = "BACKEND", default_value = "pageant")]
+    backends: Vec<BackendChain>,
+
+    /// Seconds to wait before trying a backend that failed again
+    #[clap(long, default_value = "30")]
+    retry_interval: u64,
+
+    /// Keep running and serve every client connecting to `pipe[:<named pipe>]` or
+    /// `unix:<path>`, instead of relaying a single connection on stdin and stdout
+    #[clap(long, value_name = "ADDRESS")]
+    listen: Option<ListenAddress>,
+
+    /// Answer repeated requests for the keys from memory for this many seconds, 0 never does.
+    /// Needs `--listen`, where the cache is shared by all connections
+    #[clap(long, value_name = "SECONDS", default_value = "0", requires = "listen")]
+    cache_ttl: u64,
+
+    /// Keep the keys added with ssh-add in memory and sign with them, offered after the
+    /// backends' keys. Pageant backed by gpg-agent can't add keys itself
+    #[clap(long, requires = "listen")]
+    keystore: bool,
+
+    /// Confirm signs with keys added with `ssh-add -c` with this command, like
+    /// `--approve-command`. Without a confirm command or pinentry such keys aren't added
+    #[clap(
+        long,
+        value_name = "COMMAND",
+        group = "keystore_confirmer",
+        requires = "keystore"
+    )]
+    keystore_confirm_command: Option<String>,
+
+    /// Confirm signs with keys added with `ssh-add -c` with this pinentry program
+    #[clap(
+        long,
+        value_name = "COMMAND",
+        group = "keystore_confirmer",
+        requires = "keystore"
+    )]
+    keystore_confirm_pinentry: Option<String>,
+
+    /// Refuse to sign after this many seconds without a signature, until the idle unlock command
+    /// or pinentry confirms a sign
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        requires = "listen",
+        requires = "idle_unlocker"
+    )]
+    idle_lock: Option<u64>,
+
+    /// Unlock signing after the idle lock if this command approves a sign, like
+    /// `--approve-command`
+    #[clap(
+        long,
+        value_name = "COMMAND",
+        group = "idle_unlocker",
+        requires = "idle_lock"
+    )]
+    idle_unlock_command: Option<String>,
+
+    /// Unlock signing after the idle lock if the sign is confirmed with this pinentry program
+    #[clap(
+        long,
+        value_name = "COMMAND",
+        group = "idle_unlocker",
+        requires = "idle_lock"
+    )]
+    idle_unlock_pinentry: Option<String>,
+
+    /// Refuse signs beyond this rate, e.g. `30/min` or `5/10s`. The whole count may be used at once
+    #[clap(long, value_name = "RATE", group = "rate_limit", requires = "listen")]
+    sign_limit: Option<RateLimit>,
+
+    /// Refuse signs with a key beyond this rate, counted for every key on its own
+    #[clap(long, value_name = "RATE", group = "rate_limit", requires = "listen")]
+    key_sign_limit: Option<RateLimit>,
+
+    /// Run this command when signs start being refused by a rate limit. It gets the key like
+    /// `--approve-command`, and `global` or `key` in `WSL_GPG_AGENT_LIMIT`
+    #[clap(long, value_name = "COMMAND", requires = "rate_limit")]
+    rate_limit_command: Option<String>,
+
+    /// Only offer keys matching one of these rules: `SHA256:<fingerprint>`,
+    /// `type:<key type>` or `comment:<glob>`
+    #[clap(long = "allow-key", value_name = "RULE")]
+    allow_keys: Vec<KeyRule>,
+
+    /// Never offer keys matching one of these rules, even if they're allowed
+    #[clap(long = "deny-key", value_name = "RULE")]
+    deny_keys: Vec<KeyRule>,
+
+    /// Offer the certificates in the `*-cert.pub` files of this directory with the keys they
+    /// certify, while they're valid
+    #[clap(long, value_name = "DIR")]
+    certificates: Option<PathBuf>,
+
+    /// Offer keys matching this rule first, optionally only to hosts matching one of the
+    /// patterns: `<key rule>[=<host>[,<host>...]]`. Earlier rules come first
+    #[clap(long = "prefer-key", value_name = "RULE")]
+    prefer_keys: Vec<OrderRule>,
+
+    /// Only relay listing and signing, and refuse requests that change the agent
+    #[clap(long)]
+    read_only: bool,
+
+    /// The kinds of requests relayed in read-only mode: `list`, `sign`, `add`, `remove`,
+    /// `remove-all`, `lock`, `unlock`, `extension` or `extension:<name>`
+    #[clap(
+        long = "allow-request",
+        value_name = "KIND",
+        value_delimiter = ',',
+        requires = "read_only"
+    )]
+    allow_requests: Vec<RequestKind>,
+
+    /// Only sign with keys matching the key rule for hosts matching one of the patterns:
+    /// `<key rule>=<host>[,<host>...]`, e.g. `comment:me@work=github.com,*.corp.example`
+    #[clap(long = "key-destination", value_name = "RULE")]
+    key_destinations: Vec<DestinationRule>,
+
+    /// Where to look up which host a host key belongs to [default: ~/.ssh/known_hosts]
+    #[clap(long, value_name = "PATH")]
+    known_hosts: Option<PathBuf>,
+
+    /// Refuse to sign when the agent was forwarded to a host
+    #[clap(long)]
+    refuse_forwarded: bool,
+
+    /// Run this command before every sign, a non-zero exit refuses it. It gets the key in
+    /// `WSL_GPG_AGENT_FINGERPRINT` and `WSL_GPG_AGENT_COMMENT`, and the server's host key in
+    /// `WSL_GPG_AGENT_DESTINATION` when ssh tells us
+    #[clap(long, value_name = "COMMAND", group = "approver")]
+    approve_command: Option<String>,
+
+    /// Ask for confirmation before every sign with this pinentry program
+    #[clap(long, value_name = "COMMAND", group = "approver")]
+    approve_pinentry: Option<String>,
+
+    /// Only ask for approval to sign with keys matching one of these rules
+    #[clap(long = "approve-key", value_name = "RULE", requires = "approver")]
+    approve_keys: Vec<KeyRule>,
+
+    /// Append a JSON line for every sign request to this file
+    #[clap(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Rotate the audit log once it would grow past this many bytes, 0 never rotates it
+    #[clap(long, value_name = "BYTES", default_value = "10485760")]
+    audit_log_max_size: u64,
+
+    /// How many rotated audit logs to keep
+    #[clap(long, value_name = "COUNT", default_value = "5")]
+    audit_log_keep: usize,
+}
+
+#[derive(Parser, Clone)]
+enum SshCommand {
+    ListKeys(ListKeys),
+    Sign(Sign),
+    Verify(Verify),
+}
 
 impl Ssh {
-    pub fn run(&self) -> Result<()> {
+    pub fn run(self) -> Result<()> {
         log::info!("start");
 
-        let pageant_window_name = String::from("Pageant");
-        let pageant_class_name = String::from("Pageant");
-
-        loop {
-            let stdin = io::stdin();
-            let mut reader = stdin.lock();
-            let mut stdout = io::stdout();
-            let pageant = SshPageant::new();
-            pageant.run(
-                &pageant_window_name,
-                &pageant_class_name,
-                &mut stdout,
-                &mut reader,
-            )?
+        let connect = || connect_all(&self.backends, Duration::from_secs(self.retry_interval));
+        // like a relayed connection, so the key rules, approval and audit log apply to them too
+        let connect_layered = || self.connection_layers(self.shared_layers(connect()?));
+        match &self.command {
+            Some(SshCommand::ListKeys(list_keys)) => {
+                return list_keys.run(connect_layered()?.as_mut(), &mut io::stdout())
+            }
+            Some(SshCommand::Sign(sign)) => {
+                return sign.run(
+                    connect_layered()?.as_mut(),
+                    &mut io::stdin(),
+                    &mut io::stdout(),
+                )
+            }
+            // checking a signature needs no agent
+            Some(SshCommand::Verify(verify)) => {
+                return verify.run(&SystemClock, &mut io::stdin(), &mut io::stdout())
+            }
+            None => {}
+        }
+
+        let mut backend = self.shared_layers(connect()?);
+
+        if let Some(address) = self.listen.clone() {
+            let ssh = Arc::new(self);
+            return server::serve(
+                &address,
+                backend.as_mut(),
+                Arc::new(move |backend| ssh.connection_layers(backend)),
+            )
+            .inspect_err(|e| {
+                log::error!("ssh relay failed: {e}");
+            });
+        }
+
+        let stdin = io::stdin();
+        let mut reader = stdin.lock();
+        let mut stdout = io::stdout();
+        let mut session = Session::new(self.connection_layers(backend)?);
+
+        session.run(&mut stdout, &mut reader).inspect_err(|e| {
+            log::error!("ssh session failed: {e}");
+        })
+    }
+
+    // layers whose state outlives a single connection when listening
+    fn shared_layers(&self, mut backend: Box<dyn AgentBackend>) -> Box<dyn AgentBackend> {
+        let mut cache_status = None;
+        if self.cache_ttl > 0 {
+            let cache = CacheLayer::new(
+                backend,
+                Duration::from_secs(self.cache_ttl),
+                Box::new(SystemClock),
+            );
+            cache_status = Some(cache.status());
+            backend = Box::new(cache);
+        }
+
+        // below the limits and locks, so they apply to added keys too
+        if self.keystore {
+            backend = Box::new(KeystoreLayer::new(
+                backend,
+                Box::new(SystemClock),
+                approver(
+                    &self.keystore_confirm_command,
+                    &self.keystore_confirm_pinentry,
+                ),
+            ));
+        }
+
+        if self.sign_limit.is_some() || self.key_sign_limit.is_some() {
+            let alerter = self
+                .rate_limit_command
+                .clone()
+                .map(|command| Box::new(CommandAlerter::new(command)) as Box<dyn Alerter>);
+            backend = Box::new(RateLimitLayer::new(
+                backend,
+                Box::new(SystemClock),
+                self.sign_limit,
+                self.key_sign_limit,
+                alerter,
+            ));
+        }
+
+        // a lock has to outlive the connection that set it, so only a listening relay locks itself
+        if self.listen.is_some() {
+            let idle = self.idle_lock.zip(approver(
+                &self.idle_unlock_command,
+                &self.idle_unlock_pinentry,
+            ));
+            let idle = idle.map(|(seconds, unlocker)| {
+                IdleLock::new(
+                    Duration::from_secs(seconds),
+                    Box::new(SystemClock),
+                    unlocker,
+                )
+            });
+            backend = Box::new(LockLayer::new(backend, idle));
         }
+
+        // answered here, so the status describes the relay that keeps running
+        Box::new(ExtensionLayer::new(
+            backend,
+            Box::new(SystemClock),
+            cache_status,
+        ))
+    }
+
+    // layers set up again for every client connection
+    fn connection_layers(
+        &self,
+        mut backend: Box<dyn AgentBackend>,
+    ) -> Result<Box<dyn AgentBackend>> {
+        let filter = IdentityFilter {
+            allow: self.allow_keys.clone(),
+            deny: self.deny_keys.clone(),
+        };
+        if !filter.is_empty() {
+            backend = Box::new(FilterLayer::new(backend, filter));
+        }
+
+        // read for every connection, so renewed certificates are picked up
+        if let Some(dir) = &self.certificates {
+            backend = Box::new(CertificateLayer::new(
+                backend,
+                Certificate::read_dir(dir)?,
+                Box::new(SystemClock),
+            ));
+        }
+
+        if self.read_only {
+            let allowed = if self.allow_requests.is_empty() {
+                PolicyLayer::read_only()
+            } else {
+                self.allow_requests.clone()
+            };
+            backend = Box::new(PolicyLayer::new(backend, allowed));
+        }
+
+        // outside the policy, which may refuse the session-binds the order depends on
+        if !self.prefer_keys.is_empty() {
+            let known_hosts = if self.prefer_keys.iter().all(|rule| rule.hosts.is_empty()) {
+                KnownHosts::default()
+            } else {
+                self.read_known_hosts()?
+            };
+            backend = Box::new(OrderLayer::new(
+                backend,
+                self.prefer_keys.clone(),
+                known_hosts,
+            ));
+        }
+
+        if !self.key_destinations.is_empty() || self.refuse_forwarded {
+            let known_hosts = if self.key_destinations.is_empty() {
+                KnownHosts::default()
+            } else {
+                self.read_known_hosts()?
+            };
+            backend = Box::new(DestinationLayer::new(
+                backend,
+                self.key_destinations.clone(),
+                known_hosts,
+                self.refuse_forwarded,
+            ));
+        }
+
+        if let Some(approver) = approver(&self.approve_command, &self.approve_pinentry) {
+            backend = Box::new(ApprovalLayer::new(
+                backend,
+                approver,
+                self.approve_keys.clone(),
+            ));
+        }
+
+        // the audit log wraps everything else, so it also records refused signs
+        if let Some(path) = &self.audit_log {
+            let log = AuditLog::new(path.clone(), self.audit_log_max_size, self.audit_log_keep);
+            // only to name the hosts in the records, signs are still audited without it
+            let known_hosts = self.read_known_hosts().unwrap_or_else(|e| {
+                log::warn!("the audit log won't name hosts: {e}");
+                KnownHosts::default()
+            });
+            backend = Box::new(AuditLayer::new(
+                backend,
+                log,
+                Box::new(SystemClock),
+                known_hosts,
+            ));
+        }
+
+        Ok(backend)
+    }
+
+    fn read_known_hosts(&self) -> Result<KnownHosts> {
+        let path = match &self.known_hosts {
+            Some(path) => path.clone(),
+            None => dirs::home_dir()
+                .ok_or_else(|| anyhow!("could not determine home directory"))?
+                .join(".ssh")
+                .join("known_hosts"),
+        };
+
+        KnownHosts::read(&path)
+    }
+}
+
+fn approver(command: &Option<String>, pinentry: &Option<String>) -> Option<Box<dyn Approver>> {
+    match (command, pinentry) {
+        (Some(command), _) => Some(Box::new(CommandApprover::new(command.clone()))),
+        (_, Some(command)) => Some(Box::new(PinentryApprover::new(command.clone()))),
+        _ => None,
     }
 }
 
@@ -72,3 +462,27 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> clap::error::Result<Ssh> {
+        Ssh::try_parse_from([&["ssh"], args].concat())
+    }
+
+    #[test]
+    fn test_shared_options_require_listen() {
+        // without --listen every connection starts a relay of its own, with nothing shared
+        for args in [
+            ["--cache-ttl", "60"],
+            ["--sign-limit", "30/min"],
+            ["--key-sign-limit", "5/10s"],
+        ] {
+            assert!(parse(&args).is_err(), "{args:?}");
+            assert!(parse(&[&args[..], &["--listen", "unix:/tmp/agent.sock"]].concat()).is_ok());
+        }
+
+        assert_eq!(0, parse(&[]).unwrap().cache_ttl);
+    }
+}
@@ -0,0 +1,427 @@
+use crate::ssh::key;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{Local, NaiveDateTime, TimeZone};
+use std::fs;
+use std::path::Path;
+
+// a line of an allowed_signers file, see ssh-keygen(1)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AllowedSigner {
+    // comma separated patterns, those starting with ! exclude a principal
+    pub principals: String,
+    pub cert_authority: bool,
+    // the namespaces the key may sign for, any without
+    pub namespaces: Option<String>,
+    // seconds since the epoch
+    pub valid_after: Option<u64>,
+    pub valid_before: Option<u64>,
+    pub key_blob: Vec<u8>,
+}
+
+impl AllowedSigner {
+    // `<principals> [options] <key type> <base64 key> [comment]`
+    pub fn parse(line: &str) -> Result<Self> {
+        let fields = split_fields(line)?;
+        let Some(principals) = fields.first() else {
+            bail!("expected principals and a key");
+        };
+        let principals = principals
+            .strip_prefix('"')
+            .and_then(|principals| principals.strip_suffix('"'))
+            .unwrap_or(principals);
+        if principals.is_empty() {
+            bail!("expected principals and a key");
+        }
+
+        let mut signer = Self {
+            principals: principals.to_string(),
+            ..Self::default()
+        };
+
+        // the options are optional, so the field after the principals may start the key already
+        let rest = fields[1..].join(" ");
+        if let Ok(key_blob) = key::parse_public_key(&rest) {
+            signer.key_blob = key_blob;
+            return Ok(signer);
+        }
+
+        let Some(options) = fields.get(1) else {
+            bail!("expected a key after the principals");
+        };
+        signer.parse_options(options)?;
+        signer.key_blob = key::parse_public_key(&fields[2..].join(" "))?;
+
+        Ok(signer)
+    }
+
+    fn parse_options(&mut self, options: &str) -> Result<()> {
+        for option in split_outside_quotes(options, ',') {
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(dequote(value)?)),
+                None => (option, None),
+            };
+
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("cert-authority", None) => self.cert_authority = true,
+                ("namespaces", Some(value)) => set_once(&mut self.namespaces, name, value)?,
+                ("valid-after", Some(value)) => {
+                    set_once(&mut self.valid_after, name, parse_time(&value)?)?
+                }
+                ("valid-before", Some(value)) => {
+                    set_once(&mut self.valid_before, name, parse_time(&value)?)?
+                }
+                _ => bail!("unsupported option {option}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    // whether the principal may sign for the namespace with the key at the time
+    pub fn allows(&self, principal: &str, namespace: &str, key_blob: &[u8], time: u64) -> bool {
+        // signatures made with certificates aren't supported, so certificate authorities allow nothing
+        !self.cert_authority
+            && self.key_blob == key_blob
+            && matches_pattern_list(principal, &self.principals)
+            && self
+                .namespaces
+                .as_ref()
+                .is_none_or(|namespaces| matches_pattern_list(namespace, namespaces))
+            && self
+                .valid_after
+                .is_none_or(|valid_after| time >= valid_after)
+            && self
+                .valid_before
+                .is_none_or(|valid_before| time <= valid_before)
+    }
+}
+
+// the signers of an allowed_signers file, ssh-keygen skips the lines it can't parse as well
+pub fn read(path: &Path) -> Result<Vec<AllowedSigner>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("could not read allowed signers from {}", path.display()))?;
+
+    Ok(parse(&contents, &path.display().to_string()))
+}
+
+pub fn parse(contents: &str, name: &str) -> Vec<AllowedSigner> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with('#')
+        })
+        .filter_map(|(index, line)| match AllowedSigner::parse(line) {
+            Ok(signer) => Some(signer),
+            Err(e) => {
+                log::warn!("skipping {name}:{}: {e}", index + 1);
+                None
+            }
+        })
+        .collect()
+}
+
+// YYYYMMDD[HHMM[SS]] in local time, or in UTC with a Z at the end, like ssh-keygen
+pub fn parse_time(value: &str) -> Result<u64> {
+    let (digits, utc) = match value.strip_suffix(['Z', 'z']) {
+        Some(digits) => (digits, true),
+        None => (value, false),
+    };
+    if !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        bail!("invalid time {value}");
+    }
+    let padded = match digits.len() {
+        8 => format!("{digits}000000"),
+        12 => format!("{digits}00"),
+        14 => digits.to_string(),
+        _ => bail!("invalid time {value}"),
+    };
+
+    let time = NaiveDateTime::parse_from_str(&padded, "%Y%m%d%H%M%S")
+        .map_err(|e| anyhow!("invalid time {value}: {e}"))?;
+    let seconds = if utc {
+        time.and_utc().timestamp()
+    } else {
+        Local
+            .from_local_datetime(&time)
+            .earliest()
+            .ok_or_else(|| anyhow!("time {value} doesn't exist in the local time zone"))?
+            .timestamp()
+    };
+
+    u64::try_from(seconds).map_err(|_| anyhow!("time {value} is before 1970"))
+}
+
+// like OpenSSH's match_pattern_list, one matching ! pattern excludes the value
+fn matches_pattern_list(value: &str, patterns: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split(',') {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let matches = match_pattern(value, pattern);
+
+        if matches && negated {
+            return false;
+        }
+        matched |= matches;
+    }
+
+    matched
+}
+
+// like OpenSSH's match_pattern, * matches any run of characters and ? a single one.
+// everything else, [ included, only matches itself
+fn match_pattern(value: &str, pattern: &str) -> bool {
+    let value: Vec<char> = value.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let (mut v, mut p) = (0, 0);
+    // where the last * was, and how much of the value it has taken so far
+    let mut star: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, v));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                v += 1;
+                p += 1;
+            }
+            // let the last * take one more character and try again from there
+            _ => match star {
+                Some((star_p, star_v)) => {
+                    star = Some((star_p, star_v + 1));
+                    p = star_p + 1;
+                    v = star_v + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn set_once<T>(option: &mut Option<T>, name: &str, value: T) -> Result<()> {
+    if option.is_some() {
+        bail!("{name} is given more than once");
+    }
+    *option = Some(value);
+
+    Ok(())
+}
+
+// option values have to be quoted, a quote inside them is escaped with a backslash
+fn dequote(value: &str) -> Result<String> {
+    let inner = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .ok_or_else(|| anyhow!("option value {value} isn't quoted"))?;
+
+    Ok(inner.replace("\\\"", "\""))
+}
+
+// the whitespace separated fields of a line, quoted parts may contain whitespace
+fn split_fields(line: &str) -> Result<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in line.trim().chars() {
+        if quoted {
+            field.push(c);
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => quoted = false,
+                _ => {}
+            }
+        } else if c.is_whitespace() {
+            if !field.is_empty() {
+                fields.push(std::mem::take(&mut field));
+            }
+        } else {
+            quoted = c == '"';
+            field.push(c);
+        }
+    }
+    if quoted {
+        bail!("unterminated quote");
+    }
+    if !field.is_empty() {
+        fields.push(field);
+    }
+
+    Ok(fields)
+}
+
+fn split_outside_quotes(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (index, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ if c == separator && !quoted => {
+                parts.push(&value[start..index]);
+                start = index + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+
+    parts
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::sshsig::fake::ED25519_PUBLIC_KEY;
+
+    fn signer(line: &str) -> AllowedSigner {
+        AllowedSigner::parse(line).unwrap()
+    }
+
+    fn key_blob() -> Vec<u8> {
+        key::parse_public_key(ED25519_PUBLIC_KEY).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            AllowedSigner {
+                principals: "me@laptop".to_string(),
+                key_blob: key_blob(),
+                ..AllowedSigner::default()
+            },
+            signer(&format!("me@laptop {ED25519_PUBLIC_KEY}"))
+        );
+
+        assert_eq!(
+            AllowedSigner {
+                principals: "me@laptop,*@example.com".to_string(),
+                cert_authority: true,
+                namespaces: Some("git,file".to_string()),
+                valid_after: Some(1672531200),
+                valid_before: Some(1704067199),
+                key_blob: key_blob(),
+            },
+            signer(&format!(
+                "\"me@laptop,*@example.com\" cert-authority,Namespaces=\"git,file\",\
+                 valid-after=\"20230101Z\",valid-before=\"20231231235959Z\" {ED25519_PUBLIC_KEY}"
+            ))
+        );
+
+        // quoted principals may contain whitespace
+        assert_eq!(
+            "me @laptop",
+            signer(&format!("\"me @laptop\" {ED25519_PUBLIC_KEY}")).principals
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for line in [
+            "me@laptop".to_string(),
+            format!("\"me@laptop {ED25519_PUBLIC_KEY}"),
+            format!("me@laptop unknown {ED25519_PUBLIC_KEY}"),
+            format!("me@laptop namespaces=git {ED25519_PUBLIC_KEY}"),
+            format!("me@laptop namespaces=\"git\",namespaces=\"file\" {ED25519_PUBLIC_KEY}"),
+            format!("me@laptop valid-after=\"2023\" {ED25519_PUBLIC_KEY}"),
+            format!("me@laptop cert-authority=\"yes\" {ED25519_PUBLIC_KEY}"),
+            "me@laptop ssh-ed25519 AAAA".to_string(),
+        ] {
+            assert!(AllowedSigner::parse(&line).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn test_parse_file() {
+        let contents = format!(
+            "# comment\n\nme@laptop {ED25519_PUBLIC_KEY}\nbroken\n  you@laptop {ED25519_PUBLIC_KEY}\n"
+        );
+        let signers = parse(&contents, "allowed_signers");
+
+        assert_eq!(2, signers.len());
+        assert_eq!("you@laptop", signers[1].principals);
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(1672531200, parse_time("20230101Z").unwrap());
+        assert_eq!(1672576200, parse_time("202301011230Z").unwrap());
+        assert_eq!(1672576245, parse_time("20230101123045z").unwrap());
+        let local = Local
+            .with_ymd_and_hms(2023, 1, 1, 0, 0, 0)
+            .unwrap()
+            .timestamp();
+        assert_eq!(local as u64, parse_time("20230101").unwrap());
+
+        for value in [
+            "2023010",
+            "202301011",
+            "20231301",
+            "+2023010",
+            "19691231Z",
+            "",
+        ] {
+            assert!(parse_time(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn test_matches_pattern_list() {
+        assert!(matches_pattern_list("me@laptop", "me@laptop"));
+        assert!(matches_pattern_list("me@laptop", "you@laptop,me@*"));
+        assert!(matches_pattern_list("me@laptop", "me@lapto?"));
+        assert!(!matches_pattern_list("me@laptop", "me"));
+        assert!(!matches_pattern_list("me@laptop", "*@laptop,!me@*"));
+        assert!(!matches_pattern_list("me@laptop", "!you@laptop"));
+    }
+
+    #[test]
+    fn test_match_pattern() {
+        assert!(match_pattern("me@laptop", "*"));
+        assert!(match_pattern("me@laptop", "me*top"));
+        assert!(match_pattern("me@laptop", "*a*o*"));
+        assert!(match_pattern("", "*"));
+        assert!(!match_pattern("me@laptop", "me*x"));
+        assert!(!match_pattern("me@laptop", "?"));
+
+        // OpenSSH has no character classes, brackets are only matched by themselves
+        assert!(!match_pattern("me@laptop", "me@[a-z]aptop"));
+        assert!(match_pattern("me@[a-z]aptop", "me@[a-z]aptop"));
+        assert!(!matches_pattern_list("me@laptop", "me@[a-z]aptop"));
+    }
+
+    #[test]
+    fn test_allows() {
+        let key_blob = key_blob();
+        let allowed = signer(&format!(
+            "me@laptop,*@example.com namespaces=\"git\",valid-after=\"20230101Z\",\
+             valid-before=\"20240101Z\" {ED25519_PUBLIC_KEY}"
+        ));
+        let time = 1672531200;
+
+        assert!(allowed.allows("me@laptop", "git", &key_blob, time));
+        assert!(allowed.allows("you@example.com", "git", &key_blob, 1704067200));
+        assert!(!allowed.allows("you@laptop", "git", &key_blob, time));
+        assert!(!allowed.allows("me@laptop", "file", &key_blob, time));
+        assert!(!allowed.allows("me@laptop", "git", b"other key", time));
+        assert!(!allowed.allows("me@laptop", "git", &key_blob, time - 1));
+        assert!(!allowed.allows("me@laptop", "git", &key_blob, 1704067201));
+
+        let authority = signer(&format!("me@laptop cert-authority {ED25519_PUBLIC_KEY}"));
+        assert!(!authority.allows("me@laptop", "git", &key_blob, time));
+    }
+}
@@ -0,0 +1,642 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::comments::Comments;
+use crate::ssh::destination::{Destination, SessionBinds};
+use crate::ssh::filter::KeyRule;
+use crate::ssh::key;
+use crate::ssh::protocol::{Identity, Request, Response, SignRequest};
+use anyhow::{anyhow, bail, Result};
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+
+pub const FINGERPRINT_VARIABLE: &str = "WSL_GPG_AGENT_FINGERPRINT";
+pub const COMMENT_VARIABLE: &str = "WSL_GPG_AGENT_COMMENT";
+pub const DESTINATION_VARIABLE: &str = "WSL_GPG_AGENT_DESTINATION";
+pub const FORWARDED_VARIABLE: &str = "WSL_GPG_AGENT_FORWARDED";
+
+// a sign request waiting for the user's approval
+#[derive(Debug, Clone)]
+pub struct SignApproval {
+    pub fingerprint: String,
+    pub comment: Option<String>,
+    pub destination: Option<Destination>,
+}
+
+impl SignApproval {
+    fn description(&self) -> String {
+        let mut description = match &self.comment {
+            Some(comment) => format!("Allow signing with {comment} ({})", self.fingerprint),
+            None => format!("Allow signing with {}", self.fingerprint),
+        };
+        if let Some(destination) = &self.destination {
+            let verb = if destination.forwarded {
+                "forwarded to"
+            } else {
+                "logging in to"
+            };
+            description.push_str(&format!(
+                "\n{verb} the host with key {}",
+                key::fingerprint(&destination.host_key)
+            ));
+        }
+        description.push('?');
+
+        description
+    }
+}
+
+// asks the user whether a signature may be made
+pub trait Approver {
+    fn approve(&mut self, approval: &SignApproval) -> Result<bool>;
+}
+
+// commands are run by the shell, so they may carry arguments
+pub fn shell_command(command_line: &str) -> Command {
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+
+        let mut command = Command::new("cmd");
+        command.arg("/C").raw_arg(command_line);
+        command
+    }
+    #[cfg(not(windows))]
+    {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(command_line);
+        command
+    }
+}
+
+// approves a sign if the command exits successfully
+pub struct CommandApprover {
+    command: String,
+}
+
+impl CommandApprover {
+    pub fn new(command: String) -> Self {
+        Self { command }
+    }
+}
+
+impl Approver for CommandApprover {
+    fn approve(&mut self, approval: &SignApproval) -> Result<bool> {
+        let mut command = shell_command(&self.command);
+        command
+            .env(FINGERPRINT_VARIABLE, &approval.fingerprint)
+            .env(
+                COMMENT_VARIABLE,
+                approval.comment.as_deref().unwrap_or_default(),
+            );
+        if let Some(destination) = &approval.destination {
+            command
+                .env(
+                    DESTINATION_VARIABLE,
+                    key::fingerprint(&destination.host_key),
+                )
+                .env(
+                    FORWARDED_VARIABLE,
+                    if destination.forwarded { "1" } else { "0" },
+                );
+        }
+
+        // our stdout is the client's agent connection, the command mustn't write to it
+        let output = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()?;
+        if !output.status.success() {
+            log::info!(
+                "approval command denied the sign with {}: {}, {}",
+                approval.fingerprint,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(output.status.success())
+    }
+}
+
+// asks for confirmation with a pinentry, speaking the assuan protocol to it
+pub struct PinentryApprover {
+    command: String,
+}
+
+impl PinentryApprover {
+    pub fn new(command: String) -> Self {
+        Self { command }
+    }
+}
+
+impl Approver for PinentryApprover {
+    fn approve(&mut self, approval: &SignApproval) -> Result<bool> {
+        let mut child = shell_command(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let mut input = child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("pinentry has no stdin"))?;
+        let mut output = BufReader::new(
+            child
+                .stdout
+                .take()
+                .ok_or_else(|| anyhow!("pinentry has no stdout"))?,
+        );
+
+        let confirmed = confirm(&mut input, &mut output, &approval.description());
+
+        // the pinentry quits once its input is closed, even if it didn't get to say bye
+        drop(input);
+        child.wait()?;
+
+        confirmed
+    }
+}
+
+fn confirm(input: &mut dyn Write, output: &mut dyn BufRead, description: &str) -> Result<bool> {
+    // the pinentry greets us first
+    assuan_ok(read_assuan_reply(output)?)?;
+
+    for command in [
+        String::from("SETTITLE wsl-gpg-agent"),
+        format!("SETDESC {}", assuan_escape(description)),
+        String::from("SETOK Allow"),
+        String::from("SETCANCEL Deny"),
+    ] {
+        assuan_ok(assuan_command(input, output, &command)?)?;
+    }
+
+    // an error just means the user said no, or closed the dialog
+    let confirmed = assuan_command(input, output, "CONFIRM")?.is_ok();
+    assuan_command(input, output, "BYE")?.ok();
+
+    Ok(confirmed)
+}
+
+fn assuan_command(
+    input: &mut dyn Write,
+    output: &mut dyn BufRead,
+    command: &str,
+) -> Result<std::result::Result<(), String>> {
+    input.write_all(command.as_bytes())?;
+    input.write_all(b"\n")?;
+    input.flush()?;
+
+    read_assuan_reply(output)
+}
+
+// the reply to a command is either OK or ERR, after any status and comment lines
+fn read_assuan_reply(output: &mut dyn BufRead) -> Result<std::result::Result<(), String>> {
+    loop {
+        let mut line = String::new();
+        if output.read_line(&mut line)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let line = line.trim_end();
+
+        if line == "OK" || line.starts_with("OK ") {
+            return Ok(Ok(()));
+        }
+        if line == "ERR" || line.starts_with("ERR ") {
+            return Ok(Err(line.to_string()));
+        }
+    }
+}
+
+fn assuan_ok(reply: std::result::Result<(), String>) -> Result<()> {
+    if let Err(error) = reply {
+        bail!("pinentry failed: {error}");
+    }
+
+    Ok(())
+}
+
+// assuan lines can't contain line breaks, and % starts an escape
+fn assuan_escape(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+// asks for approval before relaying a sign request
+pub struct ApprovalLayer {
+    inner: Box<dyn AgentBackend>,
+    approver: Box<dyn Approver>,
+    // only signs with keys matching one of these need approval, all of them if there are none
+    keys: Vec<KeyRule>,
+    comments: Comments,
+    binds: SessionBinds,
+}
+
+impl ApprovalLayer {
+    pub fn new(
+        inner: Box<dyn AgentBackend>,
+        approver: Box<dyn Approver>,
+        keys: Vec<KeyRule>,
+    ) -> Self {
+        Self {
+            inner,
+            approver,
+            keys,
+            comments: Comments::default(),
+            binds: SessionBinds::default(),
+        }
+    }
+
+    fn is_approved(&mut self, sign_request: &SignRequest) -> Result<bool> {
+        let comment = self
+            .comments
+            .get(self.inner.as_mut(), &sign_request.key_blob)?;
+        let identity = Identity {
+            key_blob: sign_request.key_blob.clone(),
+            comment: comment.clone().unwrap_or_default(),
+        };
+        if !self.keys.is_empty() && !self.keys.iter().any(|rule| rule.matches(&identity)) {
+            return Ok(true);
+        }
+
+        let approval = SignApproval {
+            fingerprint: key::fingerprint(&sign_request.key_blob),
+            comment,
+            destination: self.binds.destination(),
+        };
+        match self.approver.approve(&approval) {
+            Ok(approved) => Ok(approved),
+            Err(e) => {
+                log::error!("could not ask for approval: {e}");
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl AgentBackend for ApprovalLayer {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        match Request::peek_frame(request) {
+            Ok(Request::ListIdentities) => {
+                let reply = self.inner.request(request)?;
+                self.comments.observe(&reply);
+                Ok(reply)
+            }
+            Ok(Request::SignRequest(sign_request)) => {
+                if self.is_approved(&sign_request)? {
+                    self.inner.request(request)
+                } else {
+                    log::warn!(
+                        "sign with {} was not approved",
+                        key::fingerprint(&sign_request.key_blob)
+                    );
+                    Ok(Response::Failure.to_frame())
+                }
+            }
+            Ok(other) => {
+                self.binds.observe(&other);
+                self.inner.request(request)
+            }
+            Err(_) => self.inner.request(request),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use super::{Approver, SignApproval};
+    use anyhow::Result;
+    use std::sync::{Arc, Mutex};
+
+    // an approver answering from a script, remembering what it was asked
+    pub struct ScriptedApprover {
+        pub answers: Vec<Result<bool>>,
+        pub asked: Arc<Mutex<Vec<SignApproval>>>,
+    }
+
+    impl Approver for ScriptedApprover {
+        fn approve(&mut self, approval: &SignApproval) -> Result<bool> {
+            self.asked.lock().unwrap().push(approval.clone());
+            self.answers.remove(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::fake::ScriptedApprover;
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::certificate::fake::{certify, VALID_AFTER, VALID_BEFORE};
+    use crate::ssh::certificate::{Certificate, CertificateLayer};
+    use crate::ssh::clock::fake::FakeClock;
+    use crate::ssh::destination::fake::{host_key, host_key_blob, session_bind};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn identity(key_blob: &[u8], comment: &str) -> Identity {
+        Identity {
+            key_blob: key_blob.to_vec(),
+            comment: comment.to_string(),
+        }
+    }
+
+    fn sign_request(key_blob: &[u8]) -> Vec<u8> {
+        Request::SignRequest(SignRequest {
+            key_blob: key_blob.to_vec(),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .to_frame()
+    }
+
+    fn signature() -> Response {
+        Response::Signature {
+            signature: b"signature".to_vec(),
+        }
+    }
+
+    fn approval(comment: Option<&str>, destination: Option<Destination>) -> SignApproval {
+        SignApproval {
+            fingerprint: key::fingerprint(b"key"),
+            comment: comment.map(str::to_string),
+            destination,
+        }
+    }
+
+    #[cfg(unix)]
+    fn script(dir: &Path, name: &str, contents: &str) -> String {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+
+        // run through sh, so the script doesn't need to be executable
+        format!("sh '{}'", path.display())
+    }
+
+    #[test]
+    fn test_approval_layer() {
+        let backend = MockBackend::new("mock")
+            .reply(Response::IdentitiesAnswer(vec![identity(
+                b"key",
+                "me@laptop",
+            )]))
+            .reply(Response::Success)
+            .reply(signature());
+        let requests = backend.requests();
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let approver = ScriptedApprover {
+            answers: vec![Ok(false), Ok(true), Err(anyhow!("no display"))],
+            asked: asked.clone(),
+        };
+        let mut layer = ApprovalLayer::new(Box::new(backend), Box::new(approver), vec![]);
+
+        layer.request(&Request::ListIdentities.to_frame()).unwrap();
+        layer
+            .request(&session_bind(&host_key(1), false).to_frame())
+            .unwrap();
+
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&sign_request(b"key")).unwrap()
+        );
+        assert_eq!(
+            signature().to_frame(),
+            layer.request(&sign_request(b"key")).unwrap()
+        );
+        // not being able to ask counts as a no
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&sign_request(b"key")).unwrap()
+        );
+
+        // only the approved sign reached the backend
+        assert_eq!(3, requests.lock().unwrap().len());
+
+        let asked = asked.lock().unwrap();
+        assert_eq!(3, asked.len());
+        assert_eq!(Some("me@laptop".to_string()), asked[0].comment);
+        assert_eq!(
+            Some(Destination {
+                host_key: host_key_blob(&host_key(1)),
+                forwarded: false,
+            }),
+            asked[0].destination
+        );
+    }
+
+    #[test]
+    fn test_approval_only_for_matching_keys() {
+        let backend = MockBackend::new("mock")
+            .reply(Response::IdentitiesAnswer(vec![
+                identity(b"card", "cardno:000612345678"),
+                identity(b"key", "me@laptop"),
+            ]))
+            .reply(signature());
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let approver = ScriptedApprover {
+            answers: vec![Ok(false)],
+            asked: asked.clone(),
+        };
+        let keys = vec!["comment:me@*".parse().unwrap()];
+        let mut layer = ApprovalLayer::new(Box::new(backend), Box::new(approver), keys);
+
+        layer.request(&Request::ListIdentities.to_frame()).unwrap();
+        assert_eq!(
+            signature().to_frame(),
+            layer.request(&sign_request(b"card")).unwrap()
+        );
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&sign_request(b"key")).unwrap()
+        );
+        assert_eq!(1, asked.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_approval_for_certificate_of_key() {
+        let key = host_key(1);
+        let cert_blob = certify(&key, VALID_BEFORE).to_bytes().unwrap();
+        let backend = MockBackend::new("mock")
+            .reply(Response::IdentitiesAnswer(vec![identity(
+                &host_key_blob(&key),
+                "me@laptop",
+            )]))
+            .reply(signature());
+        let requests = backend.requests();
+        let certificates = CertificateLayer::new(
+            Box::new(backend),
+            vec![Certificate::decode(&cert_blob).unwrap()],
+            Box::new(FakeClock::at(UNIX_EPOCH + Duration::from_secs(VALID_AFTER))),
+        );
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let approver = ScriptedApprover {
+            answers: vec![Ok(false), Ok(true)],
+            asked: asked.clone(),
+        };
+        let keys = vec![key::fingerprint(&host_key_blob(&key)).parse().unwrap()];
+        let mut layer = ApprovalLayer::new(Box::new(certificates), Box::new(approver), keys);
+
+        // ssh signs with the certificate, the rule is for its key
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&sign_request(&cert_blob)).unwrap()
+        );
+        assert_eq!(
+            signature().to_frame(),
+            layer.request(&sign_request(&cert_blob)).unwrap()
+        );
+        assert_eq!(2, asked.lock().unwrap().len());
+        assert_eq!(
+            Some(&sign_request(&host_key_blob(&key))),
+            requests.lock().unwrap().last()
+        );
+    }
+
+    #[test]
+    fn test_approval_without_listing() {
+        let backend = MockBackend::new("mock")
+            .reply(Response::IdentitiesAnswer(vec![identity(
+                b"key",
+                "me@laptop",
+            )]))
+            .fail("pageant is gone");
+        let requests = backend.requests();
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let approver = ScriptedApprover {
+            answers: vec![Ok(false)],
+            asked: asked.clone(),
+        };
+        let keys = vec!["comment:me@*".parse().unwrap()];
+        let mut layer = ApprovalLayer::new(Box::new(backend), Box::new(approver), keys);
+
+        // the comment is looked up, so the rule still asks
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&sign_request(b"key")).unwrap()
+        );
+        assert_eq!(
+            Some("me@laptop".to_string()),
+            asked.lock().unwrap()[0].comment
+        );
+
+        // without knowing the comment, nothing is signed
+        assert!(layer.request(&sign_request(b"other")).is_err());
+        assert_eq!(2, requests.lock().unwrap().len());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_command_approver() {
+        let dir = tempfile::tempdir().unwrap();
+        let calls = dir.path().join("calls");
+        let command = script(
+            dir.path(),
+            "approve.sh",
+            &format!(
+                r#"echo "$WSL_GPG_AGENT_FINGERPRINT|$WSL_GPG_AGENT_COMMENT|${{WSL_GPG_AGENT_DESTINATION-none}}|${{WSL_GPG_AGENT_FORWARDED-none}}" >> '{}'
+test "$WSL_GPG_AGENT_COMMENT" = me@laptop
+"#,
+                calls.display()
+            ),
+        );
+        let mut approver = CommandApprover::new(command);
+
+        let destination = Destination {
+            host_key: b"host key".to_vec(),
+            forwarded: true,
+        };
+        assert!(approver
+            .approve(&approval(Some("me@laptop"), Some(destination)))
+            .unwrap());
+        assert!(!approver.approve(&approval(None, None)).unwrap());
+
+        let fingerprint = key::fingerprint(b"key");
+        let host_key = key::fingerprint(b"host key");
+        assert_eq!(
+            format!("{fingerprint}|me@laptop|{host_key}|1\n{fingerprint}||none|none\n"),
+            fs::read_to_string(calls).unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    fn pinentry(dir: &Path, confirm: &str) -> String {
+        let descriptions = dir.join("descriptions");
+        script(
+            dir,
+            "pinentry.sh",
+            &format!(
+                r##"echo "OK Pleased to meet you"
+while read -r command args; do
+    case "$command" in
+        SETDESC) echo "$args" >> '{}'; echo OK ;;
+        CONFIRM) echo "# asking"; echo "{confirm}" ;;
+        BYE) echo "OK closing connection"; exit 0 ;;
+        *) echo OK ;;
+    esac
+done
+"##,
+                descriptions.display()
+            ),
+        )
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_pinentry_approver() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut approver = PinentryApprover::new(pinentry(dir.path(), "OK"));
+
+        assert!(approver
+            .approve(&approval(Some("me@laptop"), None))
+            .unwrap());
+        assert_eq!(
+            format!(
+                "Allow signing with me@laptop ({})?\n",
+                key::fingerprint(b"key")
+            ),
+            fs::read_to_string(dir.path().join("descriptions")).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_pinentry_approver_cancelled() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut approver =
+            PinentryApprover::new(pinentry(dir.path(), "ERR 83886179 Operation cancelled"));
+
+        assert!(!approver.approve(&approval(None, None)).unwrap());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_pinentry_approver_missing() {
+        let mut approver = PinentryApprover::new(String::from("exit 1"));
+        assert!(approver.approve(&approval(None, None)).is_err());
+    }
+
+    #[test]
+    fn test_description() {
+        let destination = Destination {
+            host_key: b"host key".to_vec(),
+            forwarded: true,
+        };
+
+        assert_eq!(
+            format!(
+                "Allow signing with me@laptop ({})\nforwarded to the host with key {}?",
+                key::fingerprint(b"key"),
+                key::fingerprint(b"host key")
+            ),
+            approval(Some("me@laptop"), Some(destination)).description()
+        );
+        assert_eq!("100%25%0Asure", assuan_escape("100%\nsure"));
+    }
+}
@@ -0,0 +1,430 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::clock::Clock;
+use crate::ssh::comments::Comments;
+use crate::ssh::destination::{Destination, SessionBinds};
+use crate::ssh::key;
+use crate::ssh::known_hosts::KnownHosts;
+use crate::ssh::protocol::{self, Request, Response, SignRequest};
+use anyhow::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignResult {
+    // the backend made a signature
+    Signed,
+    // the backend or a policy answered with a failure
+    Refused,
+    // the backend couldn't be reached or replied garbage
+    Error,
+}
+
+// one line of the audit log
+#[derive(Debug, Clone, Serialize)]
+pub struct SignRecord {
+    pub timestamp: String,
+    pub fingerprint: String,
+    pub comment: Option<String>,
+    pub flags: Vec<String>,
+    pub data_length: usize,
+    pub result: SignResult,
+    pub latency_ms: u64,
+    pub destination: Option<SignDestination>,
+}
+
+// the destination of a sign with the names known_hosts has for its host key
+#[derive(Debug, Clone, Serialize)]
+pub struct SignDestination {
+    #[serde(flatten)]
+    pub destination: Destination,
+    pub hosts: Vec<String>,
+}
+
+// the names of the signature flags, as used by ssh for the algorithms
+fn flag_names(flags: u32) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = flags;
+
+    for (flag, name) in [
+        (protocol::SSH_AGENT_RSA_SHA2_256, "rsa-sha2-256"),
+        (protocol::SSH_AGENT_RSA_SHA2_512, "rsa-sha2-512"),
+    ] {
+        if flags & flag != 0 {
+            names.push(name.to_string());
+            rest &= !flag;
+        }
+    }
+    if rest != 0 {
+        names.push(format!("{rest:#x}"));
+    }
+
+    names
+}
+
+// an append only json lines file, rotated once it grows too large
+pub struct AuditLog {
+    path: PathBuf,
+    // 0 never rotates
+    max_size: u64,
+    // how many rotated files are kept next to the current one
+    keep: usize,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf, max_size: u64, keep: usize) -> Self {
+        Self {
+            path,
+            max_size,
+            keep,
+        }
+    }
+
+    pub fn append<T: Serialize>(&self, record: &T) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let size = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if self.max_size > 0 && size > 0 && size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // every relay process appends to the same file, so a record goes out in a single write
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    // audit.jsonl becomes audit.jsonl.1, audit.jsonl.1 becomes audit.jsonl.2 and so on
+    fn rotate(&self) -> Result<()> {
+        if self.keep == 0 {
+            return remove_if_exists(&self.path);
+        }
+
+        remove_if_exists(&self.rotated_path(self.keep))?;
+        for index in (1..self.keep).rev() {
+            rename_if_exists(&self.rotated_path(index), &self.rotated_path(index + 1))?;
+        }
+        rename_if_exists(&self.path, &self.rotated_path(1))
+    }
+}
+
+// another relay process may have rotated the files already
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// writes a record to the audit log for every sign request
+pub struct AuditLayer {
+    inner: Box<dyn AgentBackend>,
+    log: AuditLog,
+    clock: Box<dyn Clock>,
+    known_hosts: KnownHosts,
+    comments: Comments,
+    binds: SessionBinds,
+}
+
+impl AuditLayer {
+    pub fn new(
+        inner: Box<dyn AgentBackend>,
+        log: AuditLog,
+        clock: Box<dyn Clock>,
+        known_hosts: KnownHosts,
+    ) -> Self {
+        Self {
+            inner,
+            log,
+            clock,
+            known_hosts,
+            comments: Comments::default(),
+            binds: SessionBinds::default(),
+        }
+    }
+
+    fn sign(&mut self, request: &[u8], sign_request: &SignRequest) -> Result<Vec<u8>> {
+        let timestamp = DateTime::<Utc>::from(self.clock.system_time())
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+        // a sign the backend can't be asked about is still audited
+        let comment = self
+            .comments
+            .get(self.inner.as_mut(), &sign_request.key_blob)
+            .unwrap_or_else(|e| {
+                log::warn!("could not look up the comment of the key: {e}");
+                None
+            });
+        let start = self.clock.now();
+        let reply = self.inner.request(request);
+        let latency = self.clock.now().saturating_duration_since(start);
+
+        let result = match reply.as_deref().map(Response::from_frame) {
+            Ok(Ok(Response::Signature { .. })) => SignResult::Signed,
+            Ok(Ok(_)) => SignResult::Refused,
+            _ => SignResult::Error,
+        };
+
+        let record = SignRecord {
+            timestamp,
+            fingerprint: key::fingerprint(&sign_request.key_blob),
+            comment,
+            flags: flag_names(sign_request.flags),
+            data_length: sign_request.data.len(),
+            result,
+            latency_ms: latency.as_millis() as u64,
+            destination: self.binds.destination().map(|destination| SignDestination {
+                hosts: self.known_hosts.host_names(&destination.host_key),
+                destination,
+            }),
+        };
+        // failing to audit shouldn't lock the user out of their servers
+        if let Err(e) = self.log.append(&record) {
+            log::error!("could not write to the audit log: {e}");
+        }
+
+        reply
+    }
+}
+
+impl AgentBackend for AuditLayer {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        match Request::peek_frame(request) {
+            Ok(Request::ListIdentities) => {
+                let reply = self.inner.request(request)?;
+                self.comments.observe(&reply);
+                Ok(reply)
+            }
+            Ok(Request::SignRequest(sign_request)) => self.sign(request, &sign_request),
+            Ok(other) => {
+                self.binds.observe(&other);
+                self.inner.request(request)
+            }
+            Err(_) => self.inner.request(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::clock::fake::FakeClock;
+    use crate::ssh::destination::fake::{host_key, host_key_blob, session_bind};
+    use crate::ssh::protocol::Identity;
+    use serde_json::Value;
+    use std::time::{Duration, SystemTime};
+
+    // a backend that takes its time to answer
+    struct SlowBackend {
+        inner: MockBackend,
+        clock: FakeClock,
+        delay: Duration,
+    }
+
+    impl AgentBackend for SlowBackend {
+        fn name(&self) -> &str {
+            self.inner.name()
+        }
+
+        fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+            self.clock.advance(self.delay);
+            self.inner.request(request)
+        }
+    }
+
+    fn sign_request(key_blob: &[u8], flags: u32) -> Vec<u8> {
+        Request::SignRequest(SignRequest {
+            key_blob: key_blob.to_vec(),
+            data: vec![7; 32],
+            flags,
+        })
+        .to_frame()
+    }
+
+    fn read_records(path: &Path) -> Vec<Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_flag_names() {
+        assert!(flag_names(0).is_empty());
+        assert_eq!(vec!["rsa-sha2-256"], flag_names(2));
+        assert_eq!(vec!["rsa-sha2-256", "rsa-sha2-512"], flag_names(6));
+        assert_eq!(vec!["rsa-sha2-512", "0x9"], flag_names(13));
+    }
+
+    #[test]
+    fn test_audit_sign() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        // 2024-01-01T00:00:00Z
+        let clock = FakeClock::at(SystemTime::UNIX_EPOCH + Duration::from_secs(1704067200));
+        let backend = SlowBackend {
+            inner: MockBackend::new("mock")
+                .reply(Response::IdentitiesAnswer(vec![Identity {
+                    key_blob: b"key".to_vec(),
+                    comment: "me@laptop".to_string(),
+                }]))
+                .reply(Response::Signature {
+                    signature: b"signature".to_vec(),
+                })
+                .reply(Response::IdentitiesAnswer(vec![]))
+                .reply(Response::Failure)
+                .fail("pageant is gone"),
+            clock: clock.clone(),
+            delay: Duration::from_millis(120),
+        };
+        let log = AuditLog::new(path.clone(), 0, 0);
+        let mut layer = AuditLayer::new(
+            Box::new(backend),
+            log,
+            Box::new(clock),
+            KnownHosts::default(),
+        );
+
+        layer.request(&Request::ListIdentities.to_frame()).unwrap();
+        layer
+            .request(&sign_request(b"key", protocol::SSH_AGENT_RSA_SHA2_512))
+            .unwrap();
+        layer.request(&sign_request(b"other key", 0)).unwrap();
+        assert!(layer.request(&sign_request(b"key", 0)).is_err());
+
+        let records = read_records(&path);
+        assert_eq!(3, records.len());
+
+        let record = &records[0];
+        // taken before the backend was asked, after listing took its time
+        assert_eq!("2024-01-01T00:00:00.120Z", record["timestamp"]);
+        assert_eq!(key::fingerprint(b"key"), record["fingerprint"]);
+        assert_eq!("me@laptop", record["comment"]);
+        assert_eq!(serde_json::json!(["rsa-sha2-512"]), record["flags"]);
+        assert_eq!(32, record["data_length"]);
+        assert_eq!("signed", record["result"]);
+        assert_eq!(120, record["latency_ms"]);
+        assert!(record["destination"].is_null());
+
+        assert_eq!(key::fingerprint(b"other key"), records[1]["fingerprint"]);
+        assert!(records[1]["comment"].is_null());
+        assert_eq!("refused", records[1]["result"]);
+        assert_eq!("error", records[2]["result"]);
+    }
+
+    #[test]
+    fn test_audit_session_bind() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let backend = MockBackend::new("mock")
+            .reply(Response::Failure)
+            .reply(Response::IdentitiesAnswer(vec![Identity {
+                key_blob: b"key".to_vec(),
+                comment: "me@laptop".to_string(),
+            }]))
+            .reply(Response::Signature {
+                signature: b"signature".to_vec(),
+            });
+        let requests = backend.requests();
+        let log = AuditLog::new(path.clone(), 0, 0);
+        let host_key = host_key(1);
+        let known_hosts = KnownHosts::parse(&format!(
+            "github.com,140.82.121.4 {}\n",
+            host_key.public_key().to_openssh().unwrap()
+        ));
+        let mut layer = AuditLayer::new(
+            Box::new(backend),
+            log,
+            Box::new(FakeClock::new()),
+            known_hosts,
+        );
+
+        // the extension is still passed on, even if the backend doesn't know it
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer
+                .request(&session_bind(&host_key, true).to_frame())
+                .unwrap()
+        );
+        layer.request(&sign_request(b"key", 0)).unwrap();
+
+        // signed without listing, the comment is looked up
+        assert_eq!(3, requests.lock().unwrap().len());
+        let records = read_records(&path);
+        assert_eq!("me@laptop", records[0]["comment"]);
+        assert_eq!(
+            serde_json::json!({
+                "host_key": key::fingerprint(&host_key_blob(&host_key)),
+                "forwarded": true,
+                "hosts": ["github.com", "140.82.121.4"],
+            }),
+            records[0]["destination"]
+        );
+    }
+
+    #[test]
+    fn test_audit_log_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs").join("audit.jsonl");
+        let log = AuditLog::new(path.clone(), 20, 2);
+
+        // every record is 12 bytes long, so each one goes to a new file
+        for index in 0..4 {
+            log.append(&serde_json::json!({ "index": index })).unwrap();
+        }
+
+        let rotated = |index: usize| path.with_file_name(format!("audit.jsonl.{index}"));
+        assert_eq!("{\"index\":3}\n", fs::read_to_string(&path).unwrap());
+        assert_eq!("{\"index\":2}\n", fs::read_to_string(rotated(1)).unwrap());
+        assert_eq!("{\"index\":1}\n", fs::read_to_string(rotated(2)).unwrap());
+        assert!(!rotated(3).exists());
+    }
+
+    #[test]
+    fn test_audit_log_without_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = AuditLog::new(path.clone(), 0, 2);
+
+        for index in 0..3 {
+            log.append(&serde_json::json!({ "index": index })).unwrap();
+        }
+
+        assert_eq!(3, fs::read_to_string(&path).unwrap().lines().count());
+        assert!(!path.with_file_name("audit.jsonl.1").exists());
+    }
+}
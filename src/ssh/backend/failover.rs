@@ -0,0 +1,265 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::clock::Clock;
+use anyhow::{bail, Result};
+use std::time::{Duration, Instant};
+
+type Connect = Box<dyn FnMut() -> Result<Box<dyn AgentBackend>>>;
+
+// one backend of a failover chain, connected on first use
+pub struct Member {
+    name: String,
+    connect: Connect,
+    backend: Option<Box<dyn AgentBackend>>,
+    failed_at: Option<Instant>,
+}
+
+impl Member {
+    pub fn new(name: &str, connect: Connect) -> Self {
+        Self {
+            name: name.to_string(),
+            connect,
+            backend: None,
+            failed_at: None,
+        }
+    }
+
+    fn is_healthy(&self, now: Instant, retry_interval: Duration) -> bool {
+        match self.failed_at {
+            Some(failed_at) => now.duration_since(failed_at) >= retry_interval,
+            None => true,
+        }
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        if self.backend.is_none() {
+            self.backend = Some((self.connect)()?);
+        }
+
+        match self.backend.as_mut() {
+            Some(backend) => backend.request(request),
+            None => bail!("{} is not connected", self.name),
+        }
+    }
+}
+
+// tries backends in order until one of them answers.
+// a backend that couldn't be reached is skipped until the retry interval has passed.
+pub struct FailoverBackend {
+    name: String,
+    members: Vec<Member>,
+    retry_interval: Duration,
+    clock: Box<dyn Clock>,
+}
+
+impl FailoverBackend {
+    pub fn new(members: Vec<Member>, retry_interval: Duration, clock: Box<dyn Clock>) -> Self {
+        let name = members
+            .iter()
+            .map(|member| member.name.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        Self {
+            name,
+            members,
+            retry_interval,
+            clock,
+        }
+    }
+}
+
+impl AgentBackend for FailoverBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let now = self.clock.now();
+
+        for member in self.members.iter_mut() {
+            if !member.is_healthy(now, self.retry_interval) {
+                log::debug!("skipping {}, it failed recently", member.name);
+                continue;
+            }
+
+            match member.request(request) {
+                Ok(reply) => {
+                    if member.failed_at.take().is_some() {
+                        log::info!("{} recovered", member.name);
+                    }
+                    return Ok(reply);
+                }
+                Err(e) => {
+                    // reconnect on the next attempt, the connection may be broken
+                    log::warn!("{} failed, failing over: {e}", member.name);
+                    member.backend = None;
+                    member.failed_at = Some(now);
+                }
+            }
+        }
+
+        bail!("no backend of {} is available", self.name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::clock::fake::FakeClock;
+    use crate::ssh::protocol::{Request, Response};
+    use std::sync::{Arc, Mutex};
+
+    const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+    // a member that hands out the scripted backends one connection at a time,
+    // and can't connect once they're used up
+    fn member(name: &str, backends: Vec<MockBackend>) -> (Member, Arc<Mutex<u32>>) {
+        let connects = Arc::new(Mutex::new(0));
+        let counter = connects.clone();
+        let mut backends = backends.into_iter();
+        let member_name = name.to_string();
+
+        let member = Member::new(
+            name,
+            Box::new(move || {
+                *counter.lock().unwrap() += 1;
+                match backends.next() {
+                    Some(backend) => Ok(Box::new(backend) as Box<dyn AgentBackend>),
+                    None => bail!("{member_name} is not running"),
+                }
+            }),
+        );
+
+        (member, connects)
+    }
+
+    fn request(failover: &mut FailoverBackend) -> Result<Vec<u8>> {
+        failover.request(&Request::ListIdentities.to_frame())
+    }
+
+    #[test]
+    fn test_first_backend_answers() {
+        let (pageant, _) = member(
+            "pageant",
+            vec![MockBackend::new("pageant")
+                .reply(Response::Success)
+                .reply(Response::Success)],
+        );
+        let (gpg, gpg_connects) = member("gpg", vec![]);
+
+        let mut failover = FailoverBackend::new(
+            vec![pageant, gpg],
+            RETRY_INTERVAL,
+            Box::new(FakeClock::new()),
+        );
+        assert_eq!("pageant,gpg", failover.name());
+        assert_eq!(
+            Response::Success.to_frame(),
+            request(&mut failover).unwrap()
+        );
+        assert_eq!(
+            Response::Success.to_frame(),
+            request(&mut failover).unwrap()
+        );
+
+        // the second backend is never needed
+        assert_eq!(0, *gpg_connects.lock().unwrap());
+    }
+
+    #[test]
+    fn test_fails_over_when_connecting_fails() {
+        let (pageant, pageant_connects) = member("pageant", vec![]);
+        let (gpg, _) = member(
+            "gpg",
+            vec![MockBackend::new("gpg")
+                .reply(Response::Success)
+                .reply(Response::Failure)],
+        );
+        let clock = FakeClock::new();
+
+        let mut failover =
+            FailoverBackend::new(vec![pageant, gpg], RETRY_INTERVAL, Box::new(clock.clone()));
+        assert_eq!(
+            Response::Success.to_frame(),
+            request(&mut failover).unwrap()
+        );
+
+        // pageant failed recently, so it isn't tried again right away
+        clock.advance(RETRY_INTERVAL / 2);
+        assert_eq!(
+            Response::Failure.to_frame(),
+            request(&mut failover).unwrap()
+        );
+        assert_eq!(1, *pageant_connects.lock().unwrap());
+    }
+
+    #[test]
+    fn test_fails_over_when_request_fails() {
+        let (pageant, pageant_connects) = member(
+            "pageant",
+            vec![MockBackend::new("pageant").fail("could not send data")],
+        );
+        let (gpg, _) = member(
+            "gpg",
+            vec![MockBackend::new("gpg").reply(Response::Success)],
+        );
+
+        let mut failover = FailoverBackend::new(
+            vec![pageant, gpg],
+            RETRY_INTERVAL,
+            Box::new(FakeClock::new()),
+        );
+        assert_eq!(
+            Response::Success.to_frame(),
+            request(&mut failover).unwrap()
+        );
+        assert_eq!(1, *pageant_connects.lock().unwrap());
+    }
+
+    #[test]
+    fn test_retries_after_interval() {
+        let (pageant, pageant_connects) = member(
+            "pageant",
+            vec![
+                MockBackend::new("pageant").fail("could not send data"),
+                MockBackend::new("pageant").reply(Response::Success),
+            ],
+        );
+        let (gpg, _) = member(
+            "gpg",
+            vec![MockBackend::new("gpg").reply(Response::Failure)],
+        );
+        let clock = FakeClock::new();
+
+        let mut failover =
+            FailoverBackend::new(vec![pageant, gpg], RETRY_INTERVAL, Box::new(clock.clone()));
+        assert_eq!(
+            Response::Failure.to_frame(),
+            request(&mut failover).unwrap()
+        );
+
+        // once the interval passed, pageant is reconnected and answers again
+        clock.advance(RETRY_INTERVAL);
+        assert_eq!(
+            Response::Success.to_frame(),
+            request(&mut failover).unwrap()
+        );
+        assert_eq!(2, *pageant_connects.lock().unwrap());
+    }
+
+    #[test]
+    fn test_all_backends_failed() {
+        let (pageant, _) = member("pageant", vec![]);
+        let (gpg, gpg_connects) = member("gpg", vec![]);
+        let clock = FakeClock::new();
+
+        let mut failover =
+            FailoverBackend::new(vec![pageant, gpg], RETRY_INTERVAL, Box::new(clock.clone()));
+        assert!(request(&mut failover).is_err());
+
+        // nothing is tried while every backend is waiting for its retry
+        assert!(request(&mut failover).is_err());
+        assert_eq!(1, *gpg_connects.lock().unwrap());
+    }
+}
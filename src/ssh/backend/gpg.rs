@@ -0,0 +1,106 @@
+use crate::gpg::get_gpg_port;
+use crate::ssh::backend::stream::StreamBackend;
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+// where gpg-agent writes the socket emulation file for its ssh-agent support
+pub fn default_socket_path() -> Result<PathBuf> {
+    Ok(dirs::cache_dir()
+        .ok_or_else(|| anyhow!("could not determine cache directory"))?
+        .join("gnupg")
+        .join("S.gpg-agent.ssh"))
+}
+
+// gpg-agent's ssh-agent emulation, reached over the tcp port from its socket emulation file
+pub fn connect(path: &Path) -> Result<StreamBackend<TcpStream>> {
+    let path = path.to_path_buf();
+    let backend = StreamBackend::connect("gpg-agent", move || {
+        let path = path.clone();
+        async move {
+            // read again on every connect, a restarted gpg-agent has a new port and nonce
+            let (port, nonce) = get_gpg_port(path.clone())
+                .with_context(|| format!("could not read {}", path.display()))?;
+
+            let addr = format!("localhost:{port}");
+            let mut stream = TcpStream::connect(&addr)
+                .await
+                .with_context(|| format!("could not connect to {addr}"))?;
+
+            // gpg-agent drops connections that don't start with the nonce
+            stream.write_all(nonce.as_slice()).await?;
+            log::info!("connected to gpg-agent at {addr}");
+
+            Ok(stream)
+        }
+    })?;
+
+    Ok(backend)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::AgentBackend;
+    use crate::ssh::protocol::{Identity, Request, Response};
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_fake_agent() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let nonce = [42u8; 16];
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("S.gpg-agent.ssh");
+        fs::write(&path, [format!("{port}\n").as_bytes(), &nonce].concat()).unwrap();
+
+        let identities = vec![Identity {
+            key_blob: b"key".to_vec(),
+            comment: "cardno:000000000000".to_string(),
+        }];
+        let reply = Response::IdentitiesAnswer(identities.clone()).to_frame();
+
+        // checks the nonce, then answers a single request
+        let agent = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received_nonce = [0u8; 16];
+            stream.read_exact(&mut received_nonce).unwrap();
+
+            let mut request = [0u8; 5];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&reply).unwrap();
+
+            (received_nonce, request)
+        });
+
+        let mut backend = connect(&path).unwrap();
+        let reply = backend
+            .request(&Request::ListIdentities.to_frame())
+            .unwrap();
+        assert_eq!(
+            Response::IdentitiesAnswer(identities),
+            Response::from_frame(&reply).unwrap()
+        );
+
+        let (received_nonce, request) = agent.join().unwrap();
+        assert_eq!(nonce, received_nonce);
+        assert_eq!(Request::ListIdentities.to_frame(), request.to_vec());
+    }
+
+    #[test]
+    fn test_missing_socket_file() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(connect(&dir.path().join("S.gpg-agent.ssh")).is_err());
+    }
+
+    #[test]
+    fn test_default_socket_path() {
+        let path = default_socket_path().unwrap();
+        assert!(path.ends_with("gnupg/S.gpg-agent.ssh"));
+    }
+}
@@ -0,0 +1,65 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::protocol::Response;
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+enum Reply {
+    Frame(Vec<u8>),
+    Error(String),
+}
+
+// an in-memory backend that answers with scripted replies and records every request
+pub struct MockBackend {
+    name: String,
+    replies: VecDeque<Reply>,
+    requests: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl MockBackend {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            replies: VecDeque::new(),
+            requests: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // queues a typed reply for the next request
+    pub fn reply(self, response: Response) -> Self {
+        self.reply_frame(response.to_frame())
+    }
+
+    // queues raw bytes for the next request, which may not be a valid frame
+    pub fn reply_frame(mut self, frame: Vec<u8>) -> Self {
+        self.replies.push_back(Reply::Frame(frame));
+        self
+    }
+
+    // queues an error, as if the backend couldn't be reached
+    pub fn fail(mut self, message: &str) -> Self {
+        self.replies.push_back(Reply::Error(message.to_string()));
+        self
+    }
+
+    // a handle to the requests this backend received, usable after the backend was boxed
+    pub fn requests(&self) -> Arc<Mutex<Vec<Vec<u8>>>> {
+        self.requests.clone()
+    }
+}
+
+impl AgentBackend for MockBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.requests.lock().unwrap().push(request.to_vec());
+
+        match self.replies.pop_front() {
+            Some(Reply::Frame(frame)) => Ok(frame),
+            Some(Reply::Error(message)) => bail!("{message}"),
+            None => bail!("{} has no reply scripted", self.name),
+        }
+    }
+}
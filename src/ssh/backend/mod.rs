@@ -0,0 +1,311 @@
+use crate::ssh::backend::failover::{FailoverBackend, Member};
+use crate::ssh::backend::multi::MultiBackend;
+use crate::ssh::clock::SystemClock;
+use crate::ssh::SshPageant;
+use anyhow::{bail, Result};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub mod failover;
+pub mod gpg;
+#[cfg(test)]
+pub mod mock;
+pub mod multi;
+#[cfg(windows)]
+pub mod pipe;
+pub mod stream;
+#[cfg(unix)]
+pub mod unix;
+
+// somewhere we can relay agent requests to, e.g. pageant
+pub trait AgentBackend {
+    // used to tell backends apart in the logs
+    fn name(&self) -> &str;
+
+    // sends one framed request and returns the framed reply
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>>;
+}
+
+// a backend as given on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendSpec {
+    Pageant,
+    // gpg-agent's socket emulation file, or its default location
+    Gpg(Option<PathBuf>),
+    // a windows named pipe, or the one OpenSSH uses
+    Pipe(Option<String>),
+    Unix(PathBuf),
+}
+
+impl BackendSpec {
+    pub fn connect(&self) -> Result<Box<dyn AgentBackend>> {
+        match self {
+            Self::Pageant => {
+                let pageant_window_name = String::from("Pageant");
+                let pageant_class_name = String::from("Pageant");
+
+                Ok(Box::new(SshPageant::new(
+                    &pageant_window_name,
+                    &pageant_class_name,
+                )?))
+            }
+            Self::Gpg(Some(path)) => Ok(Box::new(gpg::connect(path)?)),
+            Self::Gpg(None) => Ok(Box::new(gpg::connect(&gpg::default_socket_path()?)?)),
+            #[cfg(windows)]
+            Self::Pipe(pipe_name) => Ok(Box::new(pipe::connect(
+                pipe_name.as_deref().unwrap_or(pipe::DEFAULT_PIPE_NAME),
+            )?)),
+            #[cfg(not(windows))]
+            Self::Pipe(_) => bail!("named pipes are only available on windows"),
+            #[cfg(unix)]
+            Self::Unix(path) => Ok(Box::new(unix::connect(path)?)),
+            #[cfg(not(unix))]
+            Self::Unix(_) => bail!("unix sockets are only available on unix"),
+        }
+    }
+}
+
+// backends tried in order until one of them answers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendChain(pub Vec<BackendSpec>);
+
+impl BackendChain {
+    pub fn connect(&self, retry_interval: Duration) -> Result<Box<dyn AgentBackend>> {
+        if let [spec] = self.0.as_slice() {
+            return spec.connect();
+        }
+
+        Ok(self.failover(retry_interval))
+    }
+
+    // connects on first use, and again once the retry interval passed while it fails
+    pub fn failover(&self, retry_interval: Duration) -> Box<dyn AgentBackend> {
+        let members = self
+            .0
+            .iter()
+            .map(|spec| {
+                let spec = spec.clone();
+                Member::new(&spec.to_string(), Box::new(move || spec.connect()))
+            })
+            .collect();
+
+        Box::new(FailoverBackend::new(
+            members,
+            retry_interval,
+            Box::new(SystemClock),
+        ))
+    }
+}
+
+impl FromStr for BackendChain {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Ok(Self(
+            value
+                .split(',')
+                .map(BackendSpec::from_str)
+                .collect::<Result<_>>()?,
+        ))
+    }
+}
+
+// connects to every chain of backends, merging them if there is more than one
+pub fn connect_all(
+    chains: &[BackendChain],
+    retry_interval: Duration,
+) -> Result<Box<dyn AgentBackend>> {
+    if let [chain] = chains {
+        return chain.connect(retry_interval);
+    }
+
+    // one agent not running shouldn't keep us from using the others, or them once they start
+    let mut backends = Vec::new();
+    let mut connected = false;
+    for chain in chains {
+        match chain.connect(retry_interval) {
+            Ok(backend) => {
+                backends.push(backend);
+                connected = true;
+            }
+            Err(e) => {
+                log::warn!("backend {chain:?} isn't available, trying it again later: {e}");
+                backends.push(chain.failover(retry_interval));
+            }
+        }
+    }
+
+    if !connected {
+        bail!("could not connect to any backend");
+    }
+
+    Ok(Box::new(MultiBackend::new(backends)))
+}
+
+impl fmt::Display for BackendSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pageant => write!(f, "pageant"),
+            Self::Gpg(None) => write!(f, "gpg"),
+            Self::Gpg(Some(path)) => write!(f, "gpg:{}", path.display()),
+            Self::Pipe(None) => write!(f, "pipe"),
+            Self::Pipe(Some(pipe_name)) => write!(f, "pipe:{pipe_name}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for BackendSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.split_once(':') {
+            None if value == "pageant" => Ok(Self::Pageant),
+            None if value == "gpg" => Ok(Self::Gpg(None)),
+            Some(("gpg", path)) if !path.is_empty() => Ok(Self::Gpg(Some(PathBuf::from(path)))),
+            None if value == "pipe" => Ok(Self::Pipe(None)),
+            Some(("pipe", pipe_name)) if !pipe_name.is_empty() => {
+                Ok(Self::Pipe(Some(pipe_name.to_string())))
+            }
+            Some(("unix", path)) if !path.is_empty() => Ok(Self::Unix(PathBuf::from(path))),
+            _ => bail!(
+                "unknown backend {value}, expected pageant, gpg[:<path>], pipe[:<name>] or unix:<path>"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(unix)]
+    use crate::ssh::protocol::{Identity, Request, Response};
+    #[cfg(unix)]
+    use std::io::{Read, Write};
+    #[cfg(unix)]
+    use std::os::unix::net::UnixListener;
+    #[cfg(unix)]
+    use std::thread;
+
+    #[test]
+    fn test_connect_all_none_available() {
+        let dir = tempfile::tempdir().unwrap();
+        let chains = vec![
+            BackendChain(vec![BackendSpec::Gpg(Some(
+                dir.path().join("S.gpg-agent.ssh"),
+            ))]),
+            BackendChain(vec![BackendSpec::Gpg(Some(dir.path().join("other")))]),
+        ];
+
+        assert!(connect_all(&chains, Duration::from_secs(30)).is_err());
+    }
+
+    // answers every request with a single identity, until the connection is closed
+    #[cfg(unix)]
+    fn fake_agent(listener: UnixListener, comment: &str) -> thread::JoinHandle<()> {
+        let reply = Response::IdentitiesAnswer(vec![Identity {
+            key_blob: comment.as_bytes().to_vec(),
+            comment: comment.to_string(),
+        }])
+        .to_frame();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut length = [0u8; 4];
+            while stream.read_exact(&mut length).is_ok() {
+                let mut request = vec![0u8; u32::from_be_bytes(length) as usize];
+                stream.read_exact(&mut request).unwrap();
+                stream.write_all(&reply).unwrap();
+            }
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_connect_all_backend_starting_later() {
+        let dir = tempfile::tempdir().unwrap();
+        let running = dir.path().join("running.sock");
+        let later = dir.path().join("later.sock");
+        let _running = fake_agent(UnixListener::bind(&running).unwrap(), "running");
+        let chains = vec![
+            BackendChain(vec![BackendSpec::Unix(running)]),
+            BackendChain(vec![BackendSpec::Unix(later.clone())]),
+        ];
+
+        let mut backend = connect_all(&chains, Duration::from_secs(30)).unwrap();
+        let _later = fake_agent(UnixListener::bind(&later).unwrap(), "later");
+
+        let reply = backend
+            .request(&Request::ListIdentities.to_frame())
+            .unwrap();
+        let Ok(Response::IdentitiesAnswer(identities)) = Response::from_frame(&reply) else {
+            panic!("no identities answer");
+        };
+        assert_eq!(
+            vec!["running", "later"],
+            identities
+                .iter()
+                .map(|identity| identity.comment.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_parse_backend_chain() {
+        assert_eq!(
+            BackendChain(vec![
+                BackendSpec::Pageant,
+                BackendSpec::Gpg(None),
+                BackendSpec::Unix(PathBuf::from("/tmp/agent.sock")),
+            ]),
+            "pageant,gpg,unix:/tmp/agent.sock".parse().unwrap()
+        );
+        assert_eq!(
+            BackendChain(vec![BackendSpec::Pageant]),
+            "pageant".parse().unwrap()
+        );
+
+        assert!("pageant,".parse::<BackendChain>().is_err());
+        assert!("pageant,foo".parse::<BackendChain>().is_err());
+    }
+
+    #[test]
+    fn test_display_backend_spec() {
+        for spec in [
+            "pageant",
+            "gpg",
+            "gpg:/tmp/S.gpg-agent.ssh",
+            "pipe",
+            "unix:/tmp/a.sock",
+        ] {
+            assert_eq!(spec, spec.parse::<BackendSpec>().unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn test_parse_backend_spec() {
+        assert_eq!(BackendSpec::Pageant, "pageant".parse().unwrap());
+        assert_eq!(
+            BackendSpec::Unix(PathBuf::from("/run/user/1000/agent.sock")),
+            "unix:/run/user/1000/agent.sock".parse().unwrap()
+        );
+
+        assert_eq!(BackendSpec::Gpg(None), "gpg".parse().unwrap());
+        assert_eq!(
+            BackendSpec::Gpg(Some(PathBuf::from(r"C:\gnupg\S.gpg-agent.ssh"))),
+            r"gpg:C:\gnupg\S.gpg-agent.ssh".parse().unwrap()
+        );
+
+        assert_eq!(BackendSpec::Pipe(None), "pipe".parse().unwrap());
+        assert_eq!(
+            BackendSpec::Pipe(Some(r"\\.\pipe\gpg-agent-ssh".to_string())),
+            r"pipe:\\.\pipe\gpg-agent-ssh".parse().unwrap()
+        );
+
+        assert!("unix:".parse::<BackendSpec>().is_err());
+        assert!("pageant:foo".parse::<BackendSpec>().is_err());
+        assert!("tcp:localhost".parse::<BackendSpec>().is_err());
+    }
+}
@@ -0,0 +1,369 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::protocol::{self, Identity, Request, Response};
+use anyhow::Result;
+use std::collections::HashMap;
+
+// merges several backends into one agent.
+//
+// identities are merged and each key remembers the backend that offered it, so signs and
+// removals go to the owner of the key. requests that don't target a key are routed as:
+// - adding a key goes to the first backend that accepts it
+// - removing all keys, lock and unlock go to every backend and only succeed if all of them do
+// - extensions go to every backend, the first successful reply wins
+// - anything else goes to the first backend
+pub struct MultiBackend {
+    name: String,
+    backends: Vec<Box<dyn AgentBackend>>,
+    // key blob to the index of the backend owning it
+    owners: HashMap<Vec<u8>, usize>,
+}
+
+impl MultiBackend {
+    pub fn new(backends: Vec<Box<dyn AgentBackend>>) -> Self {
+        let name = backends
+            .iter()
+            .map(|backend| backend.name())
+            .collect::<Vec<_>>()
+            .join("+");
+
+        Self {
+            name,
+            backends,
+            owners: HashMap::new(),
+        }
+    }
+
+    fn list_identities(&mut self, request: &[u8]) -> Response {
+        let mut identities: Vec<Identity> = Vec::new();
+        let mut answered = false;
+        self.owners.clear();
+
+        for (index, backend) in self.backends.iter_mut().enumerate() {
+            let backend_identities = match request_response(backend.as_mut(), request) {
+                Some(Response::IdentitiesAnswer(identities)) => identities,
+                _ => continue,
+            };
+            answered = true;
+
+            // the same key can be offered by several backends, the first one owns it
+            for identity in backend_identities {
+                if !self.owners.contains_key(&identity.key_blob) {
+                    self.owners.insert(identity.key_blob.clone(), index);
+                    identities.push(identity);
+                }
+            }
+        }
+
+        if answered {
+            Response::IdentitiesAnswer(identities)
+        } else {
+            Response::Failure
+        }
+    }
+
+    fn owner(&mut self, key_blob: &[u8]) -> Option<usize> {
+        // clients may use a key without listing first, e.g. ssh-keygen -Y sign
+        if !self.owners.contains_key(key_blob) {
+            self.list_identities(&Request::ListIdentities.to_frame());
+        }
+
+        self.owners.get(key_blob).copied()
+    }
+
+    fn route_to_owner(&mut self, key_blob: &[u8], request: &[u8]) -> Result<Vec<u8>> {
+        match self.owner(key_blob) {
+            Some(index) => self.backends[index].request(request),
+            None => {
+                log::warn!("no backend offers the requested key");
+                Ok(Response::Failure.to_frame())
+            }
+        }
+    }
+
+    fn first_accepting(&mut self, request: &[u8]) -> Response {
+        for backend in self.backends.iter_mut() {
+            if let Some(Response::Success) = request_response(backend.as_mut(), request) {
+                return Response::Success;
+            }
+        }
+
+        Response::Failure
+    }
+
+    fn all_accepting(&mut self, request: &[u8]) -> Response {
+        let mut response = Response::Success;
+        for backend in self.backends.iter_mut() {
+            if request_response(backend.as_mut(), request) != Some(Response::Success) {
+                response = Response::Failure;
+            }
+        }
+
+        response
+    }
+
+    fn first_extension(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut reply = None;
+        for backend in self.backends.iter_mut() {
+            let backend_reply = match backend.request(request) {
+                Ok(backend_reply) => backend_reply,
+                Err(e) => {
+                    log::warn!("{} failed: {e}", backend.name());
+                    continue;
+                }
+            };
+
+            let succeeded = matches!(
+                Response::from_frame(&backend_reply),
+                Ok(Response::Success | Response::Extension { .. })
+            );
+            if succeeded && reply.is_none() {
+                reply = Some(backend_reply);
+            }
+        }
+
+        reply.unwrap_or_else(|| Response::Failure.to_frame())
+    }
+}
+
+impl AgentBackend for MultiBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let response = match Request::peek_frame(request) {
+            Ok(Request::ListIdentities) => self.list_identities(request),
+            Ok(Request::SignRequest(sign_request)) => {
+                return self.route_to_owner(&sign_request.key_blob, request)
+            }
+            Ok(Request::RemoveIdentity { key_blob }) => {
+                let reply = self.route_to_owner(&key_blob, request);
+                self.owners.remove(&key_blob);
+                return reply;
+            }
+            // peeking leaves keys to add undecoded, only the backend that keeps one looks at it
+            Err(_) if protocol::adds_key(request) => self.first_accepting(request),
+            Ok(Request::RemoveAllIdentities | Request::Lock { .. } | Request::Unlock { .. }) => {
+                self.all_accepting(request)
+            }
+            Ok(Request::Extension(_)) => return Ok(self.first_extension(request)),
+            // unknown or undecodable requests can only be passed on as they are
+            _ => return self.backends[0].request(request),
+        };
+
+        Ok(response.to_frame())
+    }
+}
+
+// a failing backend shouldn't take the others down with it, so its errors are only logged
+fn request_response(backend: &mut dyn AgentBackend, request: &[u8]) -> Option<Response> {
+    match backend
+        .request(request)
+        .and_then(|reply| Response::from_frame(&reply))
+    {
+        Ok(response) => Some(response),
+        Err(e) => {
+            log::warn!("{} failed: {e}", backend.name());
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::protocol::{Extension, SignRequest, Writer};
+    use zeroize::Zeroizing;
+
+    fn identity(key_blob: &[u8], comment: &str) -> Identity {
+        Identity {
+            key_blob: key_blob.to_vec(),
+            comment: comment.to_string(),
+        }
+    }
+
+    fn sign_request(key_blob: &[u8]) -> Vec<u8> {
+        Request::SignRequest(SignRequest {
+            key_blob: key_blob.to_vec(),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .to_frame()
+    }
+
+    fn request(multi: &mut MultiBackend, request: &[u8]) -> Response {
+        Response::from_frame(&multi.request(request).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_merge_identities() {
+        let pageant = MockBackend::new("pageant").reply(Response::IdentitiesAnswer(vec![
+            identity(b"yubikey", "cardno:1"),
+            identity(b"shared", "from pageant"),
+        ]));
+        let deploy = MockBackend::new("deploy").reply(Response::IdentitiesAnswer(vec![
+            identity(b"shared", "from deploy"),
+            identity(b"deploy", "deploy key"),
+        ]));
+
+        let mut multi = MultiBackend::new(vec![Box::new(pageant), Box::new(deploy)]);
+        assert_eq!("pageant+deploy", multi.name());
+        assert_eq!(
+            Response::IdentitiesAnswer(vec![
+                identity(b"yubikey", "cardno:1"),
+                identity(b"shared", "from pageant"),
+                identity(b"deploy", "deploy key"),
+            ]),
+            request(&mut multi, &Request::ListIdentities.to_frame())
+        );
+    }
+
+    #[test]
+    fn test_merge_identities_skips_failing_backend() {
+        let pageant = MockBackend::new("pageant").fail("pageant is not running");
+        let deploy = MockBackend::new("deploy")
+            .reply(Response::IdentitiesAnswer(vec![identity(b"deploy", "")]));
+
+        let mut multi = MultiBackend::new(vec![Box::new(pageant), Box::new(deploy)]);
+        assert_eq!(
+            Response::IdentitiesAnswer(vec![identity(b"deploy", "")]),
+            request(&mut multi, &Request::ListIdentities.to_frame())
+        );
+    }
+
+    #[test]
+    fn test_merge_identities_all_failing() {
+        let pageant = MockBackend::new("pageant").fail("pageant is not running");
+        let deploy = MockBackend::new("deploy").reply(Response::Failure);
+
+        let mut multi = MultiBackend::new(vec![Box::new(pageant), Box::new(deploy)]);
+        assert_eq!(
+            Response::Failure,
+            request(&mut multi, &Request::ListIdentities.to_frame())
+        );
+    }
+
+    #[test]
+    fn test_sign_routed_to_owner() {
+        let pageant = MockBackend::new("pageant")
+            .reply(Response::IdentitiesAnswer(vec![identity(b"yubikey", "")]));
+        let deploy = MockBackend::new("deploy")
+            .reply(Response::IdentitiesAnswer(vec![identity(b"deploy", "")]))
+            .reply(Response::Signature {
+                signature: b"signed by deploy".to_vec(),
+            });
+        let pageant_requests = pageant.requests();
+        let deploy_requests = deploy.requests();
+
+        let mut multi = MultiBackend::new(vec![Box::new(pageant), Box::new(deploy)]);
+        request(&mut multi, &Request::ListIdentities.to_frame());
+        assert_eq!(
+            Response::Signature {
+                signature: b"signed by deploy".to_vec(),
+            },
+            request(&mut multi, &sign_request(b"deploy"))
+        );
+
+        assert_eq!(1, pageant_requests.lock().unwrap().len());
+        assert_eq!(
+            Some(&sign_request(b"deploy")),
+            deploy_requests.lock().unwrap().last()
+        );
+    }
+
+    #[test]
+    fn test_sign_without_listing_first() {
+        let pageant = MockBackend::new("pageant")
+            .reply(Response::IdentitiesAnswer(vec![identity(b"yubikey", "")]))
+            .reply(Response::Signature {
+                signature: b"signed by pageant".to_vec(),
+            });
+
+        let mut multi = MultiBackend::new(vec![Box::new(pageant)]);
+        assert_eq!(
+            Response::Signature {
+                signature: b"signed by pageant".to_vec(),
+            },
+            request(&mut multi, &sign_request(b"yubikey"))
+        );
+    }
+
+    #[test]
+    fn test_sign_unknown_key() {
+        let pageant = MockBackend::new("pageant")
+            .reply(Response::IdentitiesAnswer(vec![identity(b"yubikey", "")]));
+        let requests = pageant.requests();
+
+        let mut multi = MultiBackend::new(vec![Box::new(pageant)]);
+        assert_eq!(
+            Response::Failure,
+            request(&mut multi, &sign_request(b"other"))
+        );
+
+        // only the identities were requested, nobody was asked to sign
+        assert_eq!(
+            vec![Request::ListIdentities.to_frame()],
+            *requests.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_add_identity_first_accepting() {
+        let pageant = MockBackend::new("pageant").reply(Response::Failure);
+        let deploy = MockBackend::new("deploy").reply(Response::Success);
+        let mut add = Writer::new();
+        add.put_u8(protocol::SSH_AGENTC_ADD_IDENTITY);
+        add.put_string(b"ssh-ed25519");
+        add.put_string(&[1]);
+        add.put_string(&[2]);
+        add.put_string(b"");
+        let add = protocol::frame(&add.into_inner());
+        assert!(protocol::adds_key(&add));
+
+        let mut multi = MultiBackend::new(vec![Box::new(pageant), Box::new(deploy)]);
+        assert_eq!(Response::Success, request(&mut multi, &add));
+    }
+
+    #[test]
+    fn test_lock_all_backends() {
+        let lock = Request::Lock {
+            passphrase: Zeroizing::new(b"secret".to_vec()),
+        }
+        .to_frame();
+
+        let pageant = MockBackend::new("pageant").reply(Response::Success);
+        let deploy = MockBackend::new("deploy").reply(Response::Success);
+        let mut multi = MultiBackend::new(vec![Box::new(pageant), Box::new(deploy)]);
+        assert_eq!(Response::Success, request(&mut multi, &lock));
+
+        // a backend that stays unlocked has to be reported
+        let pageant = MockBackend::new("pageant").reply(Response::Failure);
+        let deploy = MockBackend::new("deploy").reply(Response::Success);
+        let deploy_requests = deploy.requests();
+        let mut multi = MultiBackend::new(vec![Box::new(pageant), Box::new(deploy)]);
+        assert_eq!(Response::Failure, request(&mut multi, &lock));
+        assert_eq!(vec![lock], *deploy_requests.lock().unwrap());
+    }
+
+    #[test]
+    fn test_extension_first_success() {
+        let extension = Request::Extension(Extension {
+            name: "session-bind@openssh.com".to_string(),
+            contents: vec![],
+        })
+        .to_frame();
+
+        let pageant = MockBackend::new("pageant").reply(Response::Failure);
+        let deploy = MockBackend::new("deploy").reply(Response::Success);
+        let other = MockBackend::new("other").reply(Response::ExtensionFailure);
+        let other_requests = other.requests();
+
+        let mut multi =
+            MultiBackend::new(vec![Box::new(pageant), Box::new(deploy), Box::new(other)]);
+        assert_eq!(Response::Success, request(&mut multi, &extension));
+
+        // every backend hears about the extension
+        assert_eq!(1, other_requests.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,33 @@
+use crate::ssh::backend::stream::StreamBackend;
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient};
+use windows::Win32::Foundation::ERROR_PIPE_BUSY;
+
+// the Windows OpenSSH agent, and gpg-agent with enable-win32-openssh-support, listen here
+pub const DEFAULT_PIPE_NAME: &str = r"\\.\pipe\openssh-ssh-agent";
+
+const CONNECT_ATTEMPTS: u32 = 20;
+
+pub fn connect(pipe_name: &str) -> Result<StreamBackend<NamedPipeClient>> {
+    let name = pipe_name.to_string();
+    let backend = StreamBackend::connect("openssh", move || {
+        let pipe_name = name.clone();
+        async move {
+            // every pipe instance can be busy with another client, so we wait for a free one
+            for _ in 0..CONNECT_ATTEMPTS {
+                match ClientOptions::new().open(&pipe_name) {
+                    Ok(client) => return Ok(client),
+                    Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY.0 as i32) => {}
+                    Err(e) => return Err(e).with_context(|| format!("could not open {pipe_name}")),
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+
+            bail!("{pipe_name} stayed busy")
+        }
+    })?;
+    log::info!("connected to agent at {pipe_name}");
+
+    Ok(backend)
+}
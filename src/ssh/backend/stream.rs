@@ -0,0 +1,211 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::protocol::MAX_MESSAGE_LENGTH;
+use anyhow::{bail, Result};
+use futures::future::{FutureExt, LocalBoxFuture};
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::runtime::{Builder, Runtime};
+
+type Connect<S> = Box<dyn FnMut() -> LocalBoxFuture<'static, Result<S>>>;
+
+// relays frames over a connected stream, the way ssh-agent speaks on its own socket.
+// the stream can be anything async: a unix socket, a tcp connection or a named pipe.
+pub struct StreamBackend<S> {
+    name: String,
+    runtime: Runtime,
+    connect: Connect<S>,
+    // dropped after a failed request, the next one connects again
+    stream: Option<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + 'static> StreamBackend<S> {
+    // async streams have to be opened inside the runtime that drives them,
+    // so the backend builds its runtime first and connects within it
+    pub fn connect<F, Fut>(name: &str, mut connect: F) -> Result<Self>
+    where
+        F: FnMut() -> Fut + 'static,
+        Fut: Future<Output = Result<S>> + 'static,
+    {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let mut connect: Connect<S> = Box::new(move || connect().boxed_local());
+        let stream = runtime.block_on(connect())?;
+
+        Ok(Self {
+            name: name.to_string(),
+            runtime,
+            connect,
+            stream: Some(stream),
+        })
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AgentBackend for StreamBackend<S> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => {
+                log::info!("connecting to {} again", self.name);
+                self.stream.insert(self.runtime.block_on((self.connect)())?)
+            }
+        };
+
+        let reply = self.runtime.block_on(relay(stream, request));
+        if reply.is_err() {
+            // the stream may be stuck in the middle of a frame, its next reply would be the rest of this one
+            self.stream = None;
+        }
+
+        reply
+    }
+}
+
+// writes one framed request and reads back the framed reply
+pub async fn relay<S>(stream: &mut S, request: &[u8]) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(request).await?;
+    stream.flush().await?;
+
+    read_frame(stream).await
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut length_buffer = [0u8; 4];
+    reader.read_exact(&mut length_buffer).await?;
+    let length = u32::from_be_bytes(length_buffer);
+
+    if length > MAX_MESSAGE_LENGTH {
+        bail!("reply of {length} bytes is larger than the maximum of {MAX_MESSAGE_LENGTH} bytes");
+    }
+
+    let mut frame = vec![0u8; (length + 4) as usize];
+    frame[..4].copy_from_slice(&length_buffer);
+    reader.read_exact(&mut frame[4..]).await?;
+
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::protocol::{Request, Response};
+    use futures::executor::block_on;
+    use std::collections::VecDeque;
+    use tokio::io::{duplex, DuplexStream};
+
+    // a backend on one end of in-memory pipes, one for every time it connects, with the
+    // agent's ends returned for scripting
+    fn backend(connections: usize) -> (StreamBackend<DuplexStream>, Vec<DuplexStream>) {
+        let (clients, agents): (VecDeque<_>, Vec<_>) = (0..connections)
+            .map(|_| duplex(MAX_MESSAGE_LENGTH as usize * 2))
+            .unzip();
+        let mut clients = clients;
+        let backend = StreamBackend::connect("duplex", move || {
+            let client = clients.pop_front();
+            async { client.ok_or_else(|| anyhow::anyhow!("the agent is gone")) }
+        })
+        .unwrap();
+
+        (backend, agents)
+    }
+
+    #[test]
+    fn test_request() {
+        let (mut backend, mut agents) = backend(1);
+        let mut agent = agents.remove(0);
+        let replies = [Response::Success.to_frame(), Response::Failure.to_frame()].concat();
+        block_on(agent.write_all(&replies)).unwrap();
+        let request = Request::ListIdentities.to_frame();
+
+        assert_eq!(
+            Response::Success.to_frame(),
+            backend.request(&request).unwrap()
+        );
+        assert_eq!(
+            Response::Failure.to_frame(),
+            backend.request(&request).unwrap()
+        );
+
+        let mut written = vec![0u8; request.len() * 2];
+        block_on(agent.read_exact(&mut written)).unwrap();
+        assert_eq!([request.clone(), request].concat(), written);
+    }
+
+    #[test]
+    fn test_request_truncated_reply() {
+        let (mut backend, mut agents) = backend(1);
+        let mut agent = agents.remove(0);
+        block_on(agent.write_all(&[0, 0, 0, 5, 6])).unwrap();
+        drop(agent);
+
+        assert!(backend
+            .request(&Request::ListIdentities.to_frame())
+            .is_err());
+    }
+
+    #[test]
+    fn test_request_oversized_reply() {
+        let (mut backend, mut agents) = backend(1);
+        let mut agent = agents.remove(0);
+        block_on(agent.write_all(&(MAX_MESSAGE_LENGTH + 1).to_be_bytes())).unwrap();
+
+        assert!(backend
+            .request(&Request::ListIdentities.to_frame())
+            .is_err());
+    }
+
+    #[test]
+    fn test_reconnect_after_error() {
+        let (mut backend, agents) = backend(2);
+        let [mut first, mut second] = agents.try_into().unwrap();
+        let request = Request::ListIdentities.to_frame();
+
+        // what follows the oversized reply must not be taken for the next one
+        let oversized = (MAX_MESSAGE_LENGTH + 1).to_be_bytes();
+        block_on(first.write_all(&[&oversized[..], &Response::Failure.to_frame()].concat()))
+            .unwrap();
+        block_on(second.write_all(&Response::Success.to_frame())).unwrap();
+
+        assert!(backend.request(&request).is_err());
+        assert_eq!(
+            Response::Success.to_frame(),
+            backend.request(&request).unwrap()
+        );
+
+        // the first connection was closed after the request it failed
+        let mut written = Vec::new();
+        block_on(first.read_to_end(&mut written)).unwrap();
+        assert_eq!(request, written);
+
+        // and nothing is left to connect to
+        drop(second);
+        assert!(backend.request(&request).is_err());
+        assert!(backend.request(&request).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let (mut client, mut agent) = duplex(1024);
+
+        // answers a single request, the way an agent on a socket would
+        let server = tokio::spawn(async move {
+            let request = read_frame(&mut agent).await.unwrap();
+            agent
+                .write_all(&Response::Success.to_frame())
+                .await
+                .unwrap();
+            request
+        });
+
+        let request = Request::RemoveAllIdentities.to_frame();
+        let reply = relay(&mut client, &request).await.unwrap();
+
+        assert_eq!(Response::Success.to_frame(), reply);
+        assert_eq!(request, server.await.unwrap());
+    }
+}
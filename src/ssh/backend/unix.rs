@@ -0,0 +1,141 @@
+use crate::ssh::backend::stream::StreamBackend;
+use anyhow::{Context, Result};
+use std::path::Path;
+use tokio::net::UnixStream;
+
+// an ssh-agent listening on a unix socket, e.g. OpenSSH's agent or gpg-agent's S.gpg-agent.ssh
+pub fn connect(path: &Path) -> Result<StreamBackend<UnixStream>> {
+    let socket = path.to_path_buf();
+    let backend = StreamBackend::connect("unix", move || {
+        let socket = socket.clone();
+        async move {
+            UnixStream::connect(&socket)
+                .await
+                .with_context(|| format!("could not connect to {}", socket.display()))
+        }
+    })?;
+    log::info!("connected to agent at {}", path.display());
+
+    Ok(backend)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::AgentBackend;
+    use crate::ssh::protocol::{Request, Response, SignRequest};
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::process::{Child, Command, Stdio};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_fake_agent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // answers every request with an empty identity list
+        let agent = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut requests = Vec::new();
+            loop {
+                let mut length = [0u8; 4];
+                if stream.read_exact(&mut length).is_err() {
+                    return requests;
+                }
+                let mut request = vec![0u8; u32::from_be_bytes(length) as usize];
+                stream.read_exact(&mut request).unwrap();
+                requests.push(request);
+
+                let reply = Response::IdentitiesAnswer(vec![]).to_frame();
+                stream.write_all(&reply).unwrap();
+            }
+        });
+
+        let mut backend = connect(&path).unwrap();
+        for _ in 0..2 {
+            let reply = backend
+                .request(&Request::ListIdentities.to_frame())
+                .unwrap();
+            assert_eq!(
+                Response::IdentitiesAnswer(vec![]),
+                Response::from_frame(&reply).unwrap()
+            );
+        }
+        drop(backend);
+
+        assert_eq!(
+            vec![Request::ListIdentities.encode(); 2],
+            agent.join().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_missing_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(connect(&dir.path().join("missing.sock")).is_err());
+    }
+
+    struct SshAgent(Child);
+
+    impl Drop for SshAgent {
+        fn drop(&mut self) {
+            _ = self.0.kill();
+            _ = self.0.wait();
+        }
+    }
+
+    #[test]
+    fn test_ssh_agent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+
+        // not every machine running the tests has OpenSSH installed
+        let Ok(child) = Command::new("ssh-agent")
+            .arg("-D")
+            .arg("-a")
+            .arg(&path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            return;
+        };
+        let _agent = SshAgent(child);
+
+        // the socket file shows up before the agent listens on it, so we retry until it answers
+        let mut backend = None;
+        for _ in 0..50 {
+            if let Ok(connected) = connect(&path) {
+                backend = Some(connected);
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        // a fresh agent has no keys
+        let mut backend = backend.unwrap();
+        let reply = backend
+            .request(&Request::ListIdentities.to_frame())
+            .unwrap();
+        assert_eq!(
+            Response::IdentitiesAnswer(vec![]),
+            Response::from_frame(&reply).unwrap()
+        );
+
+        // and nothing to sign with
+        let reply = backend
+            .request(
+                &Request::SignRequest(SignRequest {
+                    key_blob: b"unknown".to_vec(),
+                    data: b"data".to_vec(),
+                    flags: 0,
+                })
+                .to_frame(),
+            )
+            .unwrap();
+        assert_eq!(Response::Failure, Response::from_frame(&reply).unwrap());
+    }
+}
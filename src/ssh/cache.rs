@@ -0,0 +1,270 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::clock::Clock;
+use crate::ssh::policy::RequestKind;
+use crate::ssh::protocol::{Request, Response};
+use anyhow::Result;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct CachedIdentities {
+    reply: Vec<u8>,
+    key_blobs: HashSet<Vec<u8>>,
+}
+
+// when the cached identities were fetched, readable by the layers wrapping the cache
+#[derive(Clone)]
+pub struct CacheStatus {
+    pub ttl: Duration,
+    fetched_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl CacheStatus {
+    // how old the cached identities are at `now`, if they're still used
+    pub fn age(&self, now: Instant) -> Option<Duration> {
+        let fetched_at = (*self.fetched_at.lock().unwrap())?;
+        let age = now.saturating_duration_since(fetched_at);
+
+        (age < self.ttl).then_some(age)
+    }
+
+    fn set(&self, fetched_at: Option<Instant>) {
+        *self.fetched_at.lock().unwrap() = fetched_at;
+    }
+}
+
+// answers repeated identity requests from memory, so the backend isn't asked on every connection
+pub struct CacheLayer {
+    inner: Box<dyn AgentBackend>,
+    clock: Box<dyn Clock>,
+    cached: Option<CachedIdentities>,
+    status: CacheStatus,
+}
+
+impl CacheLayer {
+    pub fn new(inner: Box<dyn AgentBackend>, ttl: Duration, clock: Box<dyn Clock>) -> Self {
+        Self {
+            inner,
+            clock,
+            cached: None,
+            status: CacheStatus {
+                ttl,
+                fetched_at: Arc::new(Mutex::new(None)),
+            },
+        }
+    }
+
+    // how old the cached identities are, if they're still used
+    pub fn age(&self) -> Option<Duration> {
+        self.status.age(self.clock.now())
+    }
+
+    // a handle to the state of the cache, usable after the layer was boxed
+    pub fn status(&self) -> CacheStatus {
+        self.status.clone()
+    }
+
+    fn invalidate(&mut self, reason: &str) {
+        self.status.set(None);
+        if self.cached.take().is_some() {
+            log::debug!("dropping cached identities, {reason}");
+        }
+    }
+
+    fn list_identities(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        if self.age().is_some() {
+            if let Some(cached) = &self.cached {
+                return Ok(cached.reply.clone());
+            }
+        }
+
+        let reply = self.inner.request(request)?;
+        self.cached = match Response::from_frame(&reply) {
+            Ok(Response::IdentitiesAnswer(identities)) => Some(CachedIdentities {
+                reply: reply.clone(),
+                key_blobs: identities
+                    .into_iter()
+                    .map(|identity| identity.key_blob)
+                    .collect(),
+            }),
+            _ => None,
+        };
+        self.status
+            .set(self.cached.as_ref().map(|_| self.clock.now()));
+
+        Ok(reply)
+    }
+}
+
+impl AgentBackend for CacheLayer {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        match Request::peek_frame(request) {
+            Ok(Request::ListIdentities) => self.list_identities(request),
+            Ok(Request::SignRequest(sign_request)) => {
+                let reply = self.inner.request(request);
+
+                // the key may have been added since we asked, or the list may be wrong otherwise
+                let signed = matches!(
+                    reply.as_deref().map(Response::from_frame),
+                    Ok(Ok(Response::Signature { .. }))
+                );
+                let known = self
+                    .cached
+                    .as_ref()
+                    .is_some_and(|cached| cached.key_blobs.contains(&sign_request.key_blob));
+                if !signed && !known {
+                    self.invalidate("sign with an unknown key failed");
+                }
+
+                reply
+            }
+            _ => {
+                if RequestKind::of(request).map_or(true, |kind| kind.is_mutating()) {
+                    self.invalidate("the request may change the identities");
+                }
+                self.inner.request(request)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::clock::fake::FakeClock;
+    use crate::ssh::destination::fake::{host_key, session_bind};
+    use crate::ssh::protocol::{Identity, SignRequest};
+
+    fn identities() -> Response {
+        Response::IdentitiesAnswer(vec![Identity {
+            key_blob: b"key".to_vec(),
+            comment: "me@laptop".to_string(),
+        }])
+    }
+
+    fn sign_request(key_blob: &[u8]) -> Vec<u8> {
+        Request::SignRequest(SignRequest {
+            key_blob: key_blob.to_vec(),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .to_frame()
+    }
+
+    fn list() -> Vec<u8> {
+        Request::ListIdentities.to_frame()
+    }
+
+    #[test]
+    fn test_cache_identities() {
+        let backend = MockBackend::new("mock")
+            .reply(identities())
+            .reply(Response::IdentitiesAnswer(vec![]));
+        let requests = backend.requests();
+        let clock = FakeClock::new();
+        let mut layer = CacheLayer::new(
+            Box::new(backend),
+            Duration::from_secs(60),
+            Box::new(clock.clone()),
+        );
+
+        assert_eq!(None, layer.age());
+        assert_eq!(identities().to_frame(), layer.request(&list()).unwrap());
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(identities().to_frame(), layer.request(&list()).unwrap());
+        assert_eq!(Some(Duration::from_secs(59)), layer.age());
+        assert_eq!(1, requests.lock().unwrap().len());
+
+        // once the ttl is over, we ask again
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(None, layer.age());
+        assert_eq!(
+            Response::IdentitiesAnswer(vec![]).to_frame(),
+            layer.request(&list()).unwrap()
+        );
+        assert_eq!(2, requests.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_failures_are_not_cached() {
+        let backend = MockBackend::new("mock")
+            .reply(Response::Failure)
+            .fail("pageant is gone")
+            .reply(identities());
+        let mut layer = CacheLayer::new(
+            Box::new(backend),
+            Duration::from_secs(60),
+            Box::new(FakeClock::new()),
+        );
+
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&list()).unwrap()
+        );
+        assert!(layer.request(&list()).is_err());
+        assert_eq!(identities().to_frame(), layer.request(&list()).unwrap());
+    }
+
+    #[test]
+    fn test_invalidate_on_failed_sign_with_unknown_key() {
+        let backend = MockBackend::new("mock")
+            .reply(identities())
+            .reply(Response::Failure)
+            .reply(identities())
+            .reply(Response::Failure)
+            .reply(identities());
+        let requests = backend.requests();
+        let mut layer = CacheLayer::new(
+            Box::new(backend),
+            Duration::from_secs(60),
+            Box::new(FakeClock::new()),
+        );
+
+        layer.request(&list()).unwrap();
+
+        // a known key failing to sign, e.g. because the card was pulled, keeps the cache
+        layer.request(&sign_request(b"key")).unwrap();
+        layer.request(&list()).unwrap();
+        assert_eq!(2, requests.lock().unwrap().len());
+
+        layer.request(&sign_request(b"new key")).unwrap();
+        layer.request(&list()).unwrap();
+        assert_eq!(4, requests.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_invalidate_on_mutating_request() {
+        let backend = MockBackend::new("mock")
+            .reply(identities())
+            .reply(Response::Success)
+            .reply(identities())
+            .reply(Response::Success)
+            .reply(identities());
+        let requests = backend.requests();
+        let mut layer = CacheLayer::new(
+            Box::new(backend),
+            Duration::from_secs(60),
+            Box::new(FakeClock::new()),
+        );
+
+        layer.request(&list()).unwrap();
+        layer
+            .request(&Request::RemoveAllIdentities.to_frame())
+            .unwrap();
+        layer.request(&list()).unwrap();
+        assert_eq!(3, requests.lock().unwrap().len());
+
+        // binding the connection to a host doesn't change the identities
+        layer
+            .request(&session_bind(&host_key(1), false).to_frame())
+            .unwrap();
+        layer.request(&list()).unwrap();
+        assert_eq!(4, requests.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,427 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::clock::Clock;
+use crate::ssh::protocol::{Identity, Reader, Request, Response, SignRequest, Writer};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CERT_SUFFIX: &str = "-cert-v01@openssh.com";
+
+// how many public key fields follow the nonce of a certificate, by the type of the certified key
+fn public_key_fields(key_type: &str) -> Option<usize> {
+    match key_type {
+        "ssh-ed25519" => Some(1),
+        "ssh-rsa" => Some(2),
+        key_type if key_type.starts_with("ecdsa-sha2-") => Some(2),
+        "sk-ssh-ed25519@openssh.com" => Some(2),
+        "sk-ecdsa-sha2-nistp256@openssh.com" => Some(3),
+        "ssh-dss" => Some(4),
+        _ => None,
+    }
+}
+
+// an openssh certificate for one of the keys of the agent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    pub blob: Vec<u8>,
+    // the blob of the certified key, as the agent knows it
+    pub key_blob: Vec<u8>,
+    pub valid_after: u64,
+    pub valid_before: u64,
+}
+
+impl Certificate {
+    // the blob layout is described in PROTOCOL.certkeys of openssh
+    pub fn decode(blob: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(blob);
+        let cert_type = reader.read_utf8()?;
+        let key_type = match cert_type.strip_suffix(CERT_SUFFIX) {
+            Some(key_type) if key_type.starts_with("sk-") => format!("{key_type}@openssh.com"),
+            Some(key_type) => key_type.to_string(),
+            None => bail!("{cert_type} isn't a certificate"),
+        };
+        let fields = public_key_fields(&key_type)
+            .ok_or_else(|| anyhow!("unsupported certificate type {cert_type}"))?;
+
+        let _nonce = reader.read_string()?;
+        let start = reader.position();
+        for _ in 0..fields {
+            reader.read_string()?;
+        }
+        let mut key = Writer::new();
+        key.put_string(key_type.as_bytes());
+        key.put_raw(&blob[start..reader.position()]);
+
+        let _serial = reader.read_u64()?;
+        let _cert_type = reader.read_u32()?;
+        let _key_id = reader.read_string()?;
+        let _principals = reader.read_string()?;
+
+        Ok(Self {
+            blob: blob.to_vec(),
+            key_blob: key.into_inner(),
+            valid_after: reader.read_u64()?,
+            valid_before: reader.read_u64()?,
+        })
+    }
+
+    // a line of a -cert.pub file
+    pub fn parse(line: &str) -> Result<Self> {
+        let mut parts = line.split_whitespace();
+        let (Some(cert_type), Some(data)) = (parts.next(), parts.next()) else {
+            bail!("expected a certificate type and its base64 data");
+        };
+        let certificate = Self::decode(&STANDARD.decode(data)?)?;
+
+        let blob_type = Reader::new(&certificate.blob).read_utf8()?;
+        if blob_type != cert_type {
+            bail!("certificate of type {blob_type} is labelled {cert_type}");
+        }
+
+        Ok(certificate)
+    }
+
+    // every *-cert.pub file in the directory, skipping those that can't be read
+    pub fn read_dir(dir: &Path) -> Result<Vec<Self>> {
+        let entries = fs::read_dir(dir)
+            .with_context(|| format!("could not read certificates from {}", dir.display()))?;
+
+        let mut certificates = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if !path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.ends_with("-cert.pub"))
+            {
+                continue;
+            }
+
+            match fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|line| Self::parse(&line))
+            {
+                Ok(certificate) => certificates.push(certificate),
+                Err(e) => log::warn!("skipping certificate {}: {e}", path.display()),
+            }
+        }
+
+        Ok(certificates)
+    }
+
+    // openssh uses the certificate from valid_after up to, but not including, valid_before
+    pub fn is_valid_at(&self, time: SystemTime) -> bool {
+        let now = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+
+        self.valid_after <= now && now < self.valid_before
+    }
+}
+
+// the blob of the key a certificate blob certifies, none for a plain key
+pub fn certified_key(blob: &[u8]) -> Option<Vec<u8>> {
+    let cert_type = Reader::new(blob).read_utf8().ok()?;
+    if !cert_type.ends_with(CERT_SUFFIX) {
+        return None;
+    }
+
+    Certificate::decode(blob)
+        .map(|certificate| certificate.key_blob)
+        .ok()
+}
+
+// offers certificates next to the keys they certify, and signs with the key for them
+pub struct CertificateLayer {
+    inner: Box<dyn AgentBackend>,
+    certificates: Vec<Certificate>,
+    clock: Box<dyn Clock>,
+}
+
+impl CertificateLayer {
+    pub fn new(
+        inner: Box<dyn AgentBackend>,
+        certificates: Vec<Certificate>,
+        clock: Box<dyn Clock>,
+    ) -> Self {
+        Self {
+            inner,
+            certificates,
+            clock,
+        }
+    }
+
+    fn valid_certificates(&self) -> impl Iterator<Item = &Certificate> {
+        let now = self.clock.system_time();
+        self.certificates
+            .iter()
+            .filter(move |certificate| certificate.is_valid_at(now))
+    }
+
+    // each certificate follows the list, named like the key it certifies
+    fn add_certificates(&self, identities: Vec<Identity>) -> Vec<Identity> {
+        let mut certified = Vec::new();
+        for certificate in self.valid_certificates() {
+            let offered =
+                |blob: &[u8]| identities.iter().find(|identity| identity.key_blob == blob);
+            if offered(&certificate.blob).is_some() {
+                continue;
+            }
+
+            if let Some(key) = offered(&certificate.key_blob) {
+                certified.push(Identity {
+                    key_blob: certificate.blob.clone(),
+                    comment: key.comment.clone(),
+                });
+            }
+        }
+
+        [identities, certified].concat()
+    }
+}
+
+impl AgentBackend for CertificateLayer {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        match Request::peek_frame(request) {
+            Ok(Request::ListIdentities) => {
+                let reply = self.inner.request(request)?;
+                match Response::from_frame(&reply) {
+                    Ok(Response::IdentitiesAnswer(identities)) => Ok(Response::IdentitiesAnswer(
+                        self.add_certificates(identities),
+                    )
+                    .to_frame()),
+                    _ => Ok(reply),
+                }
+            }
+            Ok(Request::SignRequest(sign_request)) => {
+                let key_blob = self
+                    .valid_certificates()
+                    .find(|certificate| certificate.blob == sign_request.key_blob)
+                    .map(|certificate| certificate.key_blob.clone());
+
+                match key_blob {
+                    // the signature of a certificate is made by its key
+                    Some(key_blob) => self.inner.request(
+                        &Request::SignRequest(SignRequest {
+                            key_blob,
+                            ..sign_request
+                        })
+                        .to_frame(),
+                    ),
+                    None => self.inner.request(request),
+                }
+            }
+            _ => self.inner.request(request),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use crate::ssh::destination::fake::host_key;
+    use ssh_key::certificate::Builder;
+    use ssh_key::PrivateKey;
+
+    pub const VALID_AFTER: u64 = 1_700_000_000;
+    pub const VALID_BEFORE: u64 = 1_700_086_400;
+
+    // a certificate for the key, signed by host_key(99)
+    pub fn certify(key: &PrivateKey, valid_before: u64) -> ssh_key::Certificate {
+        let mut builder = Builder::new(
+            [7u8; 16],
+            key.public_key().key_data().clone(),
+            VALID_AFTER,
+            valid_before,
+        )
+        .unwrap();
+        builder.key_id("me").unwrap();
+        builder.valid_principal("me").unwrap();
+        builder.comment("me@laptop").unwrap();
+
+        builder.sign(&host_key(99)).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::fake::{certify, VALID_AFTER, VALID_BEFORE};
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::clock::fake::FakeClock;
+    use crate::ssh::destination::fake::{host_key, host_key_blob};
+    use std::time::Duration;
+
+    fn identity(key_blob: Vec<u8>, comment: &str) -> Identity {
+        Identity {
+            key_blob,
+            comment: comment.to_string(),
+        }
+    }
+
+    fn clock_at(secs: u64) -> FakeClock {
+        FakeClock::at(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn test_parse_certificate() {
+        let key = host_key(1);
+        let cert = certify(&key, VALID_BEFORE);
+        let certificate = Certificate::parse(&cert.to_openssh().unwrap()).unwrap();
+
+        assert_eq!(cert.to_bytes().unwrap(), certificate.blob);
+        assert_eq!(host_key_blob(&key), certificate.key_blob);
+        assert_eq!(VALID_AFTER, certificate.valid_after);
+        assert_eq!(VALID_BEFORE, certificate.valid_before);
+
+        let line = cert
+            .to_openssh()
+            .unwrap()
+            .replacen("ssh-ed25519", "ssh-rsa", 1);
+        assert!(Certificate::parse(&line).is_err());
+        assert!(Certificate::parse(&key.public_key().to_openssh().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_certified_key() {
+        let key = host_key(1);
+        let cert_blob = certify(&key, VALID_BEFORE).to_bytes().unwrap();
+
+        assert_eq!(Some(host_key_blob(&key)), certified_key(&cert_blob));
+        assert_eq!(None, certified_key(&host_key_blob(&key)));
+        assert_eq!(None, certified_key(b"key"));
+    }
+
+    #[test]
+    fn test_certificate_valid_forever() {
+        // ssh-keygen's default validity ends at the largest u64, which is as valid as it gets
+        let cert = certify(&host_key(1), VALID_BEFORE);
+        let mut blob = cert.to_bytes().unwrap();
+        let position = blob
+            .windows(8)
+            .position(|window| window == VALID_BEFORE.to_be_bytes())
+            .unwrap();
+        blob[position..position + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+
+        let certificate = Certificate::decode(&blob).unwrap();
+        assert_eq!(u64::MAX, certificate.valid_before);
+        assert!(certificate.is_valid_at(SystemTime::now()));
+    }
+
+    #[test]
+    fn test_is_valid_at() {
+        let certificate =
+            Certificate::decode(&certify(&host_key(1), VALID_BEFORE).to_bytes().unwrap()).unwrap();
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+
+        assert!(!certificate.is_valid_at(at(VALID_AFTER - 1)));
+        assert!(certificate.is_valid_at(at(VALID_AFTER)));
+        assert!(certificate.is_valid_at(at(VALID_BEFORE - 1)));
+        assert!(!certificate.is_valid_at(at(VALID_BEFORE)));
+    }
+
+    #[test]
+    fn test_read_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = certify(&host_key(1), VALID_BEFORE);
+        fs::write(
+            dir.path().join("id_ed25519-cert.pub"),
+            cert.to_openssh().unwrap(),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("id_ed25519.pub"),
+            host_key(1).public_key().to_openssh().unwrap(),
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("broken-cert.pub"),
+            "ssh-ed25519-cert-v01@openssh.com AAAA",
+        )
+        .unwrap();
+
+        let certificates = Certificate::read_dir(dir.path()).unwrap();
+        assert_eq!(1, certificates.len());
+        assert_eq!(cert.to_bytes().unwrap(), certificates[0].blob);
+    }
+
+    #[test]
+    fn test_add_certificates_for_offered_keys() {
+        let (key, other, unknown) = (host_key(1), host_key(2), host_key(3));
+        let certificates = [
+            certify(&key, VALID_BEFORE),
+            // already expired
+            certify(&other, VALID_AFTER + 60),
+            certify(&unknown, VALID_BEFORE),
+        ]
+        .iter()
+        .map(|cert| Certificate::decode(&cert.to_bytes().unwrap()).unwrap())
+        .collect();
+        let identities = vec![
+            identity(host_key_blob(&key), "cardno:0001"),
+            identity(host_key_blob(&other), "cardno:0002"),
+        ];
+        let backend =
+            MockBackend::new("mock").reply(Response::IdentitiesAnswer(identities.clone()));
+        let mut layer = CertificateLayer::new(
+            Box::new(backend),
+            certificates,
+            Box::new(clock_at(VALID_AFTER + 3600)),
+        );
+
+        let reply = layer.request(&Request::ListIdentities.to_frame()).unwrap();
+        let expected = [
+            identities,
+            vec![identity(
+                certify(&key, VALID_BEFORE).to_bytes().unwrap(),
+                "cardno:0001",
+            )],
+        ]
+        .concat();
+        assert_eq!(Response::IdentitiesAnswer(expected).to_frame(), reply);
+    }
+
+    #[test]
+    fn test_sign_with_certificate() {
+        let key = host_key(1);
+        let cert_blob = certify(&key, VALID_BEFORE).to_bytes().unwrap();
+        let certificate = Certificate::decode(&cert_blob).unwrap();
+        let sign_request = |key_blob: Vec<u8>| {
+            Request::SignRequest(SignRequest {
+                key_blob,
+                data: b"data".to_vec(),
+                flags: 0,
+            })
+            .to_frame()
+        };
+        let backend = MockBackend::new("mock")
+            .reply(Response::Signature {
+                signature: b"signature".to_vec(),
+            })
+            .reply(Response::Failure);
+        let requests = backend.requests();
+        let clock = clock_at(VALID_AFTER);
+        let mut layer = CertificateLayer::new(
+            Box::new(backend),
+            vec![certificate],
+            Box::new(clock.clone()),
+        );
+
+        layer.request(&sign_request(cert_blob.clone())).unwrap();
+
+        // once the certificate expired, the blob is relayed as it is and the backend refuses it
+        clock.advance(Duration::from_secs(VALID_BEFORE - VALID_AFTER));
+        layer.request(&sign_request(cert_blob.clone())).unwrap();
+
+        assert_eq!(
+            vec![sign_request(host_key_blob(&key)), sign_request(cert_blob)],
+            *requests.lock().unwrap()
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use std::time::{Instant, SystemTime};
+
+// where the relay gets the time from, so time based behaviour can be tested
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    // the wall clock time, for things that expire at a date like certificates
+    fn system_time(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use super::Clock;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime};
+
+    // a clock that only moves when told to, clones share the same time
+    #[derive(Clone)]
+    pub struct FakeClock {
+        now: Arc<Mutex<(Instant, SystemTime)>>,
+    }
+
+    impl FakeClock {
+        pub fn new() -> Self {
+            Self::at(SystemTime::now())
+        }
+
+        // a clock whose wall clock starts at the given time
+        pub fn at(system_time: SystemTime) -> Self {
+            Self {
+                now: Arc::new(Mutex::new((Instant::now(), system_time))),
+            }
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            let mut now = self.now.lock().unwrap();
+            now.0 += duration;
+            now.1 += duration;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.now.lock().unwrap().0
+        }
+
+        fn system_time(&self) -> SystemTime {
+            self.now.lock().unwrap().1
+        }
+    }
+}
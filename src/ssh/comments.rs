@@ -0,0 +1,108 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::protocol::{Identity, Request, Response};
+use anyhow::Result;
+use std::collections::HashMap;
+
+// the comments of the identities the backend offered, sign requests only carry the key
+#[derive(Debug, Clone, Default)]
+pub struct Comments {
+    comments: HashMap<Vec<u8>, String>,
+}
+
+impl Comments {
+    // remembers the comments of an identities answer relayed to the client
+    pub fn observe(&mut self, reply: &[u8]) {
+        if let Ok(Response::IdentitiesAnswer(identities)) = Response::from_frame(reply) {
+            self.comments = identities
+                .into_iter()
+                .map(|identity| (identity.key_blob, identity.comment))
+                .collect();
+        }
+    }
+
+    // the comment of a key, the identities are listed again for a key that wasn't offered yet.
+    // clients may use a key without listing first, e.g. ssh-keygen -Y sign
+    pub fn get(
+        &mut self,
+        backend: &mut dyn AgentBackend,
+        key_blob: &[u8],
+    ) -> Result<Option<String>> {
+        if !self.comments.contains_key(key_blob) {
+            let reply = backend.request(&Request::ListIdentities.to_frame())?;
+            self.observe(&reply);
+        }
+
+        Ok(self.comments.get(key_blob).cloned())
+    }
+
+    // the key as an identity key rules can match, without a comment if the backend doesn't offer it
+    pub fn identity(
+        &mut self,
+        backend: &mut dyn AgentBackend,
+        key_blob: &[u8],
+    ) -> Result<Identity> {
+        Ok(Identity {
+            key_blob: key_blob.to_vec(),
+            comment: self.get(backend, key_blob)?.unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+
+    fn identities(comment: &str) -> Response {
+        Response::IdentitiesAnswer(vec![Identity {
+            key_blob: b"key".to_vec(),
+            comment: comment.to_string(),
+        }])
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut backend = MockBackend::new("mock")
+            .reply(identities("me@laptop"))
+            .reply(identities("me@laptop"))
+            .fail("pageant is gone");
+        let requests = backend.requests();
+        let mut comments = Comments::default();
+
+        // listed on the first sign
+        assert_eq!(
+            Some("me@laptop".to_string()),
+            comments.get(&mut backend, b"key").unwrap()
+        );
+        assert_eq!(
+            Some("me@laptop".to_string()),
+            comments.get(&mut backend, b"key").unwrap()
+        );
+        assert_eq!(1, requests.lock().unwrap().len());
+
+        // a key the backend doesn't offer is looked up every time
+        assert_eq!(
+            "",
+            comments.identity(&mut backend, b"other").unwrap().comment
+        );
+        assert!(comments.get(&mut backend, b"other").is_err());
+        assert_eq!(3, requests.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_observe() {
+        let mut backend = MockBackend::new("mock");
+        let mut comments = Comments::default();
+
+        comments.observe(&identities("me@laptop").to_frame());
+        comments.observe(&Response::Failure.to_frame());
+        assert_eq!(
+            Some("me@laptop".to_string()),
+            comments.get(&mut backend, b"key").unwrap()
+        );
+
+        // a new answer replaces the old one
+        comments.observe(&Response::IdentitiesAnswer(vec![]).to_frame());
+        assert!(comments.get(&mut backend, b"key").is_err());
+    }
+}
@@ -0,0 +1,665 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::comments::Comments;
+use crate::ssh::filter::KeyRule;
+use crate::ssh::key;
+use crate::ssh::known_hosts::KnownHosts;
+use crate::ssh::protocol::{
+    Reader, Request, Response, SessionBind, SignRequest, SESSION_BIND_EXTENSION,
+};
+use anyhow::{bail, Result};
+use glob::Pattern;
+use serde::{Serialize, Serializer};
+use signature::Verifier;
+use ssh_key::{PublicKey, Signature};
+use std::str::FromStr;
+
+const SSH2_MSG_USERAUTH_REQUEST: u8 = 50;
+const HOSTBOUND_METHOD: &[u8] = b"publickey-hostbound-v00@openssh.com";
+
+// the host a connection was bound to with session-bind@openssh.com
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Destination {
+    #[serde(serialize_with = "serialize_fingerprint")]
+    pub host_key: Vec<u8>,
+    // whether the agent was forwarded to get there
+    pub forwarded: bool,
+}
+
+fn serialize_fingerprint<S: Serializer>(key_blob: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&key::fingerprint(key_blob))
+}
+
+// the session-binds ssh sent on a connection, it binds it again for every host the agent is forwarded to
+#[derive(Debug, Clone, Default)]
+pub struct SessionBinds {
+    binds: Vec<SessionBind>,
+}
+
+impl SessionBinds {
+    // records the request if it's a session-bind, returns whether it was accepted
+    pub fn observe(&mut self, request: &Request) -> Option<bool> {
+        let Request::Extension(extension) = request else {
+            return None;
+        };
+        if extension.name != SESSION_BIND_EXTENSION {
+            return None;
+        }
+
+        match SessionBind::decode(&extension.contents).and_then(|bind| self.bind(bind)) {
+            Ok(()) => Some(true),
+            Err(e) => {
+                log::warn!("refusing {SESSION_BIND_EXTENSION}: {e}");
+                Some(false)
+            }
+        }
+    }
+
+    fn bind(&mut self, bind: SessionBind) -> Result<()> {
+        // like ssh-agent, a connection used to log in somewhere can't be moved to another host
+        if self.binds.last().is_some_and(|last| !last.is_forwarding) {
+            bail!("the connection is already bound for authentication");
+        }
+
+        // the host proves it holds its key by signing the session id
+        let host_key = PublicKey::from_bytes(&bind.host_key)?;
+        let signature = Signature::try_from(bind.signature.as_slice())?;
+        Verifier::verify(host_key.key_data(), &bind.session_id, &signature)?;
+
+        self.binds.push(bind);
+        Ok(())
+    }
+
+    // the last host the connection was bound to, where signatures end up being used
+    pub fn destination(&self) -> Option<Destination> {
+        self.binds.last().map(|last| Destination {
+            host_key: last.host_key.clone(),
+            forwarded: self.is_forwarded(),
+        })
+    }
+
+    pub fn is_forwarded(&self) -> bool {
+        self.binds.iter().any(|bind| bind.is_forwarding)
+    }
+
+    // like ssh-agent, only logging in to the host the connection was last bound to is signed
+    fn check_userauth(&self, sign_request: &SignRequest) -> Result<()> {
+        let Some(last) = self.binds.last() else {
+            bail!("the connection isn't bound to a host");
+        };
+
+        let userauth = UserauthRequest::parse(&sign_request.data, &sign_request.key_blob)?;
+        if userauth.session_id != last.session_id {
+            bail!("the data is signed for another session");
+        }
+        if userauth
+            .host_key
+            .is_some_and(|host_key| host_key != last.host_key)
+        {
+            bail!("the data is signed for another host");
+        }
+
+        Ok(())
+    }
+}
+
+// the publickey userauth request ssh asks the agent to sign when logging in
+struct UserauthRequest<'a> {
+    session_id: &'a [u8],
+    // only in publickey-hostbound-v00@openssh.com requests
+    host_key: Option<&'a [u8]>,
+}
+
+impl<'a> UserauthRequest<'a> {
+    fn parse(data: &'a [u8], key_blob: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(data);
+        let session_id = reader.read_string()?;
+        if reader.read_u8()? != SSH2_MSG_USERAUTH_REQUEST {
+            bail!("the data isn't a userauth request");
+        }
+        // the user
+        reader.read_string()?;
+        if reader.read_string()? != b"ssh-connection" {
+            bail!("the userauth request isn't for ssh-connection");
+        }
+        let method = reader.read_string()?;
+        if method != b"publickey" && method != HOSTBOUND_METHOD {
+            bail!("the userauth request isn't for a public key");
+        }
+        if !reader.read_bool()? {
+            bail!("the userauth request doesn't have a signature");
+        }
+        // the signature algorithm
+        reader.read_string()?;
+        if reader.read_string()? != key_blob {
+            bail!("the userauth request is for another key");
+        }
+        let host_key = match method == HOSTBOUND_METHOD {
+            true => Some(reader.read_string()?),
+            false => None,
+        };
+        reader.finish()?;
+
+        Ok(Self {
+            session_id,
+            host_key,
+        })
+    }
+}
+
+// keys matching the rule may only be used for hosts matching one of the patterns
+#[derive(Debug, Clone)]
+pub struct DestinationRule {
+    pub keys: KeyRule,
+    pub hosts: Vec<Pattern>,
+}
+
+impl FromStr for DestinationRule {
+    type Err = anyhow::Error;
+
+    // <key rule>=<host pattern>[,<host pattern>...], host names never contain =
+    fn from_str(value: &str) -> Result<Self> {
+        let Some((keys, hosts)) = value.rsplit_once('=') else {
+            bail!(
+                "destination rule {value} is missing hosts, expected <key rule>=<host>[,<host>...]"
+            );
+        };
+
+        let hosts = hosts
+            .split(',')
+            .filter(|host| !host.is_empty())
+            .map(Pattern::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        if hosts.is_empty() {
+            bail!(
+                "destination rule {value} is missing hosts, expected <key rule>=<host>[,<host>...]"
+            );
+        }
+
+        Ok(Self {
+            keys: keys.parse()?,
+            hosts,
+        })
+    }
+}
+
+// refuses signs for hosts a key isn't meant for
+pub struct DestinationLayer {
+    inner: Box<dyn AgentBackend>,
+    rules: Vec<DestinationRule>,
+    known_hosts: KnownHosts,
+    refuse_forwarded: bool,
+    binds: SessionBinds,
+    // comment rules need them to judge signs
+    comments: Comments,
+}
+
+impl DestinationLayer {
+    pub fn new(
+        inner: Box<dyn AgentBackend>,
+        rules: Vec<DestinationRule>,
+        known_hosts: KnownHosts,
+        refuse_forwarded: bool,
+    ) -> Self {
+        Self {
+            inner,
+            rules,
+            known_hosts,
+            refuse_forwarded,
+            binds: SessionBinds::default(),
+            comments: Comments::default(),
+        }
+    }
+
+    fn deny_reason(&mut self, sign_request: &SignRequest) -> Result<Option<String>> {
+        if self.refuse_forwarded && self.binds.is_forwarded() {
+            return Ok(Some(String::from("the agent was forwarded")));
+        }
+        if self.rules.is_empty() {
+            return Ok(None);
+        }
+
+        let identity = self
+            .comments
+            .identity(self.inner.as_mut(), &sign_request.key_blob)?;
+        let rules: Vec<&DestinationRule> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.keys.matches(&identity))
+            .collect();
+        if rules.is_empty() {
+            return Ok(None);
+        }
+
+        let Some(destination) = self.binds.destination() else {
+            return Ok(Some(String::from("the connection isn't bound to a host")));
+        };
+        let allowed = rules.iter().any(|rule| {
+            rule.hosts
+                .iter()
+                .any(|host| self.known_hosts.is_known_as(&destination.host_key, host))
+        });
+        if !allowed {
+            return Ok(Some(format!(
+                "host key {} doesn't belong to an allowed host",
+                key::fingerprint(&destination.host_key)
+            )));
+        }
+        if let Err(e) = self.binds.check_userauth(sign_request) {
+            return Ok(Some(e.to_string()));
+        }
+
+        Ok(None)
+    }
+}
+
+impl AgentBackend for DestinationLayer {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        match Request::peek_frame(request) {
+            Ok(Request::ListIdentities) => {
+                let reply = self.inner.request(request)?;
+                self.comments.observe(&reply);
+                Ok(reply)
+            }
+            Ok(Request::SignRequest(sign_request)) => match self.deny_reason(&sign_request)? {
+                Some(reason) => {
+                    log::warn!(
+                        "refusing to sign with {}: {reason}",
+                        key::fingerprint(&sign_request.key_blob)
+                    );
+                    Ok(Response::Failure.to_frame())
+                }
+                None => self.inner.request(request),
+            },
+            Ok(other) => match self.binds.observe(&other) {
+                // the backend doesn't need to see binds we don't believe either
+                Some(false) => Ok(Response::Failure.to_frame()),
+                _ => self.inner.request(request),
+            },
+            Err(_) => self.inner.request(request),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use crate::ssh::protocol::{Extension, Request, SessionBind, SESSION_BIND_EXTENSION};
+    use signature::Signer;
+    use ssh_key::private::Ed25519Keypair;
+    use ssh_key::{PrivateKey, Signature};
+
+    // an ed25519 host key, the same for the same seed
+    pub fn host_key(seed: u8) -> PrivateKey {
+        Ed25519Keypair::from_seed(&[seed; 32]).into()
+    }
+
+    pub fn host_key_blob(host_key: &PrivateKey) -> Vec<u8> {
+        host_key.public_key().to_bytes().unwrap()
+    }
+
+    // a session-bind properly signed by the host
+    pub fn session_bind(host_key: &PrivateKey, is_forwarding: bool) -> Request {
+        let session_id = b"session id".to_vec();
+        let signature: Signature = Signer::sign(host_key, &session_id);
+        let session_bind = SessionBind {
+            host_key: host_key_blob(host_key),
+            session_id,
+            signature: Vec::try_from(signature).unwrap(),
+            is_forwarding,
+        };
+
+        Request::Extension(Extension {
+            name: SESSION_BIND_EXTENSION.to_string(),
+            contents: session_bind.encode(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::fake::{host_key, host_key_blob, session_bind};
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::protocol::{Extension, Identity, Writer};
+
+    fn sign_request(key_blob: &[u8]) -> Vec<u8> {
+        Request::SignRequest(SignRequest {
+            key_blob: key_blob.to_vec(),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .to_frame()
+    }
+
+    // what ssh signs to log in with the key over the session
+    fn userauth_data(session_id: &[u8], key_blob: &[u8], host_key: Option<&[u8]>) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.put_string(session_id);
+        writer.put_u8(SSH2_MSG_USERAUTH_REQUEST);
+        writer.put_string(b"me");
+        writer.put_string(b"ssh-connection");
+        match host_key {
+            Some(_) => writer.put_string(HOSTBOUND_METHOD),
+            None => writer.put_string(b"publickey"),
+        }
+        writer.put_bool(true);
+        writer.put_string(b"ssh-ed25519");
+        writer.put_string(key_blob);
+        if let Some(host_key) = host_key {
+            writer.put_string(host_key);
+        }
+
+        writer.into_inner()
+    }
+
+    fn userauth_sign_request(
+        session_id: &[u8],
+        key_blob: &[u8],
+        host_key: Option<&[u8]>,
+    ) -> Vec<u8> {
+        Request::SignRequest(SignRequest {
+            key_blob: key_blob.to_vec(),
+            data: userauth_data(session_id, key_blob, host_key),
+            flags: 0,
+        })
+        .to_frame()
+    }
+
+    fn known_hosts() -> KnownHosts {
+        let github = host_key(1).public_key().to_openssh().unwrap();
+        let example = host_key(2).public_key().to_openssh().unwrap();
+        KnownHosts::parse(&format!(
+            "github.com,140.82.121.4 {github}\nexample.com {example}\n"
+        ))
+    }
+
+    fn layer(backend: MockBackend, rules: &[&str], refuse_forwarded: bool) -> DestinationLayer {
+        let rules = rules.iter().map(|rule| rule.parse().unwrap()).collect();
+        DestinationLayer::new(Box::new(backend), rules, known_hosts(), refuse_forwarded)
+    }
+
+    fn identity(key_blob: &[u8], comment: &str) -> Identity {
+        Identity {
+            key_blob: key_blob.to_vec(),
+            comment: comment.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_session_binds() {
+        let mut binds = SessionBinds::default();
+        assert_eq!(None, binds.observe(&Request::ListIdentities));
+        assert_eq!(None, binds.destination());
+
+        // forwarded to the jump host, then logging in to the next one from there
+        assert_eq!(Some(true), binds.observe(&session_bind(&host_key(1), true)));
+        assert_eq!(
+            Some(true),
+            binds.observe(&session_bind(&host_key(2), false))
+        );
+        assert_eq!(
+            Some(Destination {
+                host_key: host_key_blob(&host_key(2)),
+                forwarded: true,
+            }),
+            binds.destination()
+        );
+
+        // the connection was used to log in, it can't be bound again
+        assert_eq!(
+            Some(false),
+            binds.observe(&session_bind(&host_key(3), false))
+        );
+        assert_eq!(
+            Some(host_key_blob(&host_key(2))),
+            binds.destination().map(|destination| destination.host_key)
+        );
+    }
+
+    #[test]
+    fn test_session_bind_bad_signature() {
+        let mut binds = SessionBinds::default();
+
+        // another host's signature doesn't prove anything
+        let Request::Extension(mut extension) = session_bind(&host_key(1), false) else {
+            unreachable!();
+        };
+        let mut forged = SessionBind::decode(&extension.contents).unwrap();
+        forged.host_key = host_key_blob(&host_key(2));
+        extension.contents = forged.encode();
+        assert_eq!(Some(false), binds.observe(&Request::Extension(extension)));
+
+        let broken = Request::Extension(Extension {
+            name: SESSION_BIND_EXTENSION.to_string(),
+            contents: vec![0, 0, 0, 9],
+        });
+        assert_eq!(Some(false), binds.observe(&broken));
+        assert_eq!(None, binds.destination());
+    }
+
+    #[test]
+    fn test_serialize_destination() {
+        let destination = Destination {
+            host_key: b"host key".to_vec(),
+            forwarded: false,
+        };
+
+        assert_eq!(
+            serde_json::json!({
+                "host_key": key::fingerprint(b"host key"),
+                "forwarded": false,
+            }),
+            serde_json::to_value(&destination).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_destination_rule() {
+        let rule: DestinationRule = "comment:me@work=github.com,*.example.com".parse().unwrap();
+        assert!(matches!(rule.keys, KeyRule::Comment(_)));
+        assert_eq!(2, rule.hosts.len());
+
+        assert!("comment:me@work".parse::<DestinationRule>().is_err());
+        assert!("comment:me@work=".parse::<DestinationRule>().is_err());
+        assert!("md5:00=github.com".parse::<DestinationRule>().is_err());
+    }
+
+    #[test]
+    fn test_restrict_key_to_host() {
+        let backend = MockBackend::new("mock")
+            .reply(Response::IdentitiesAnswer(vec![
+                identity(b"work", "me@work"),
+                identity(b"home", "me@home"),
+            ]))
+            .reply(Response::Success)
+            .reply(Response::Success)
+            .reply(Response::Success);
+        let requests = backend.requests();
+        let mut layer = layer(backend, &["comment:me@work=github.com"], false);
+
+        layer.request(&Request::ListIdentities.to_frame()).unwrap();
+
+        // without knowing where the signature goes, the work key can't be used
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&sign_request(b"work")).unwrap()
+        );
+        assert_eq!(
+            Response::Success.to_frame(),
+            layer.request(&sign_request(b"home")).unwrap()
+        );
+
+        layer
+            .request(&session_bind(&host_key(2), false).to_frame())
+            .unwrap();
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&sign_request(b"work")).unwrap()
+        );
+        assert_eq!(
+            Response::Success.to_frame(),
+            layer.request(&sign_request(b"home")).unwrap()
+        );
+
+        assert_eq!(4, requests.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_allow_key_for_host() {
+        let backend = MockBackend::new("mock")
+            .reply(Response::IdentitiesAnswer(vec![identity(
+                b"work", "me@work",
+            )]))
+            .reply(Response::Success)
+            .reply(Response::Success)
+            .reply(Response::Success);
+        let requests = backend.requests();
+        let mut layer = layer(backend, &["comment:me@work=github.com"], false);
+        let github = host_key_blob(&host_key(1));
+
+        layer.request(&Request::ListIdentities.to_frame()).unwrap();
+        layer
+            .request(&session_bind(&host_key(1), false).to_frame())
+            .unwrap();
+        assert_eq!(
+            Response::Success.to_frame(),
+            layer
+                .request(&userauth_sign_request(b"session id", b"work", None))
+                .unwrap()
+        );
+        assert_eq!(
+            Response::Success.to_frame(),
+            layer
+                .request(&userauth_sign_request(
+                    b"session id",
+                    b"work",
+                    Some(&github)
+                ))
+                .unwrap()
+        );
+        assert_eq!(4, requests.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_restrict_key_to_bound_session() {
+        let backend = MockBackend::new("mock")
+            .reply(Response::IdentitiesAnswer(vec![identity(
+                b"work", "me@work",
+            )]))
+            .reply(Response::Success);
+        let requests = backend.requests();
+        let mut layer = layer(backend, &["comment:me@work=github.com"], false);
+        let example = host_key_blob(&host_key(2));
+
+        layer.request(&Request::ListIdentities.to_frame()).unwrap();
+        layer
+            .request(&session_bind(&host_key(1), false).to_frame())
+            .unwrap();
+
+        // bound for github.com, but the signature is for logging in somewhere else
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer
+                .request(&userauth_sign_request(b"another session", b"work", None))
+                .unwrap()
+        );
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer
+                .request(&userauth_sign_request(
+                    b"session id",
+                    b"work",
+                    Some(&example)
+                ))
+                .unwrap()
+        );
+        let for_another_key = Request::SignRequest(SignRequest {
+            key_blob: b"work".to_vec(),
+            data: userauth_data(b"session id", b"home", None),
+            flags: 0,
+        });
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&for_another_key.to_frame()).unwrap()
+        );
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&sign_request(b"work")).unwrap()
+        );
+        assert_eq!(2, requests.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_restrict_key_without_listing() {
+        let backend =
+            MockBackend::new("mock")
+                .reply(Response::Success)
+                .reply(Response::IdentitiesAnswer(vec![identity(
+                    b"work", "me@work",
+                )]));
+        let requests = backend.requests();
+        let mut layer = layer(backend, &["comment:me@work=github.com"], false);
+
+        // the comment is looked up before deciding
+        layer
+            .request(&session_bind(&host_key(2), false).to_frame())
+            .unwrap();
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&sign_request(b"work")).unwrap()
+        );
+        assert_eq!(
+            vec![
+                session_bind(&host_key(2), false).to_frame(),
+                Request::ListIdentities.to_frame()
+            ],
+            *requests.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_refuse_forwarded() {
+        let backend = MockBackend::new("mock")
+            .reply(Response::Success)
+            .reply(Response::Success);
+        let requests = backend.requests();
+        let mut layer = layer(backend, &[], true);
+
+        assert_eq!(
+            Response::Success.to_frame(),
+            layer.request(&sign_request(b"key")).unwrap()
+        );
+        layer
+            .request(&session_bind(&host_key(1), true).to_frame())
+            .unwrap();
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&sign_request(b"key")).unwrap()
+        );
+        assert_eq!(2, requests.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_forged_session_bind_is_not_relayed() {
+        let backend = MockBackend::new("mock");
+        let requests = backend.requests();
+        let mut layer = layer(backend, &[], false);
+        let Request::Extension(mut extension) = session_bind(&host_key(1), false) else {
+            unreachable!();
+        };
+        let mut forged = SessionBind::decode(&extension.contents).unwrap();
+        forged.session_id = b"another session".to_vec();
+        extension.contents = forged.encode();
+
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer
+                .request(&Request::Extension(extension).to_frame())
+                .unwrap()
+        );
+        assert!(requests.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,312 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::cache::CacheStatus;
+use crate::ssh::clock::Clock;
+use crate::ssh::protocol::{
+    Extension, Reader, Request, Response, Writer, QUERY_EXTENSION, STATUS_EXTENSION,
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::time::Instant;
+
+// the extensions the relay answers itself
+const RELAY_EXTENSIONS: [&str; 2] = [QUERY_EXTENSION, STATUS_EXTENSION];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CacheReport {
+    pub ttl_secs: u64,
+    // none while nothing is cached
+    pub age_secs: Option<u64>,
+}
+
+// the answer to status@wsl-gpg-agent, as json
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StatusReport {
+    pub version: String,
+    pub backend: String,
+    pub uptime_secs: u64,
+    // none when caching is turned off
+    pub cache: Option<CacheReport>,
+}
+
+// the contents of a query reply, the names of the supported extensions one after the other
+fn encode_query(names: &BTreeSet<String>) -> Vec<u8> {
+    let mut writer = Writer::new();
+    for name in names {
+        writer.put_string(name.as_bytes());
+    }
+
+    writer.into_inner()
+}
+
+fn decode_query(contents: &[u8]) -> Result<Vec<String>> {
+    let mut reader = Reader::new(contents);
+    let mut names = Vec::new();
+    while !reader.is_empty() {
+        names.push(reader.read_utf8()?);
+    }
+
+    Ok(names)
+}
+
+// answers the extensions about the relay itself, and relays everything else
+pub struct ExtensionLayer {
+    inner: Box<dyn AgentBackend>,
+    clock: Box<dyn Clock>,
+    started_at: Instant,
+    cache: Option<CacheStatus>,
+}
+
+impl ExtensionLayer {
+    pub fn new(
+        inner: Box<dyn AgentBackend>,
+        clock: Box<dyn Clock>,
+        cache: Option<CacheStatus>,
+    ) -> Self {
+        Self {
+            started_at: clock.now(),
+            inner,
+            clock,
+            cache,
+        }
+    }
+
+    pub fn status(&self) -> StatusReport {
+        let now = self.clock.now();
+
+        StatusReport {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            backend: self.inner.name().to_string(),
+            uptime_secs: now.saturating_duration_since(self.started_at).as_secs(),
+            cache: self.cache.as_ref().map(|cache| CacheReport {
+                ttl_secs: cache.ttl.as_secs(),
+                age_secs: cache.age(now).map(|age| age.as_secs()),
+            }),
+        }
+    }
+
+    fn query(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let mut names: BTreeSet<String> = RELAY_EXTENSIONS
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+
+        // a backend that doesn't know the query extension, or can't be reached, just has none to add
+        let reply = self.inner.request(request).unwrap_or_else(|e| {
+            log::warn!("could not ask the backend for its extensions: {e}");
+            Response::Failure.to_frame()
+        });
+        if let Ok(Response::Extension { contents }) = Response::from_frame(&reply) {
+            match decode_query(&contents) {
+                Ok(backend_names) => names.extend(backend_names),
+                Err(e) => log::warn!("invalid {QUERY_EXTENSION} reply from backend: {e}"),
+            }
+        }
+
+        Ok(Response::Extension {
+            contents: encode_query(&names),
+        }
+        .to_frame())
+    }
+
+    fn report_status(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        // behind us may be the relay that keeps running, its status is the interesting one.
+        // a backend that's down doesn't stop us from reporting ours
+        match self.inner.request(request) {
+            Ok(reply) if matches!(Response::from_frame(&reply), Ok(Response::Extension { .. })) => {
+                return Ok(reply);
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("could not ask the backend for its status: {e}"),
+        }
+
+        let mut writer = Writer::new();
+        writer.put_string(&serde_json::to_vec(&self.status())?);
+
+        Ok(Response::Extension {
+            contents: writer.into_inner(),
+        }
+        .to_frame())
+    }
+}
+
+impl AgentBackend for ExtensionLayer {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        match Request::peek_frame(request) {
+            Ok(Request::Extension(Extension { name, .. })) if name == QUERY_EXTENSION => {
+                self.query(request)
+            }
+            Ok(Request::Extension(Extension { name, .. })) if name == STATUS_EXTENSION => {
+                self.report_status(request)
+            }
+            _ => self.inner.request(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::cache::CacheLayer;
+    use crate::ssh::clock::fake::FakeClock;
+    use crate::ssh::protocol::Identity;
+    use std::time::Duration;
+
+    fn extension(name: &str) -> Vec<u8> {
+        Request::Extension(Extension {
+            name: name.to_string(),
+            contents: vec![],
+        })
+        .to_frame()
+    }
+
+    fn query_answer(reply: &[u8]) -> Vec<String> {
+        match Response::from_frame(reply).unwrap() {
+            Response::Extension { contents } => decode_query(&contents).unwrap(),
+            response => panic!("unexpected reply {response}"),
+        }
+    }
+
+    #[test]
+    fn test_query_merges_backend_extensions() {
+        let names = BTreeSet::from([
+            "session-bind@openssh.com".to_string(),
+            QUERY_EXTENSION.to_string(),
+        ]);
+        let backend = MockBackend::new("mock").reply(Response::Extension {
+            contents: encode_query(&names),
+        });
+        let requests = backend.requests();
+        let mut layer = ExtensionLayer::new(Box::new(backend), Box::new(FakeClock::new()), None);
+
+        assert_eq!(
+            vec![
+                QUERY_EXTENSION,
+                "session-bind@openssh.com",
+                STATUS_EXTENSION
+            ],
+            query_answer(&layer.request(&extension(QUERY_EXTENSION)).unwrap())
+        );
+        assert_eq!(vec![extension(QUERY_EXTENSION)], *requests.lock().unwrap());
+    }
+
+    #[test]
+    fn test_query_without_backend_support() {
+        for reply in [Response::Failure, Response::ExtensionFailure] {
+            let backend = MockBackend::new("mock").reply(reply);
+            let mut layer =
+                ExtensionLayer::new(Box::new(backend), Box::new(FakeClock::new()), None);
+
+            assert_eq!(
+                vec![QUERY_EXTENSION, STATUS_EXTENSION],
+                query_answer(&layer.request(&extension(QUERY_EXTENSION)).unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn test_answer_without_backend() {
+        let backend = MockBackend::new("pageant")
+            .fail("pageant isn't running")
+            .fail("pageant isn't running");
+        let mut layer = ExtensionLayer::new(Box::new(backend), Box::new(FakeClock::new()), None);
+
+        assert_eq!(
+            vec![QUERY_EXTENSION, STATUS_EXTENSION],
+            query_answer(&layer.request(&extension(QUERY_EXTENSION)).unwrap())
+        );
+        let reply = layer.request(&extension(STATUS_EXTENSION)).unwrap();
+        assert!(matches!(
+            Response::from_frame(&reply).unwrap(),
+            Response::Extension { .. }
+        ));
+    }
+
+    #[test]
+    fn test_status() {
+        let backend = MockBackend::new("pageant")
+            .reply(Response::IdentitiesAnswer(vec![Identity {
+                key_blob: b"key".to_vec(),
+                comment: "me@laptop".to_string(),
+            }]))
+            .reply(Response::Failure);
+        let requests = backend.requests();
+        let clock = FakeClock::new();
+        let cache = CacheLayer::new(
+            Box::new(backend),
+            Duration::from_secs(60),
+            Box::new(clock.clone()),
+        );
+        let status = cache.status();
+        let mut layer = ExtensionLayer::new(Box::new(cache), Box::new(clock.clone()), Some(status));
+
+        clock.advance(Duration::from_secs(5));
+        layer.request(&Request::ListIdentities.to_frame()).unwrap();
+        clock.advance(Duration::from_secs(3));
+        layer.request(&Request::ListIdentities.to_frame()).unwrap();
+
+        let reply = layer.request(&extension(STATUS_EXTENSION)).unwrap();
+        let contents = match Response::from_frame(&reply).unwrap() {
+            Response::Extension { contents } => contents,
+            response => panic!("unexpected reply {response}"),
+        };
+        let mut reader = Reader::new(&contents);
+        let status: serde_json::Value =
+            serde_json::from_slice(reader.read_string().unwrap()).unwrap();
+        reader.finish().unwrap();
+
+        assert_eq!(
+            serde_json::json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "backend": "pageant",
+                "uptime_secs": 8,
+                "cache": {"ttl_secs": 60, "age_secs": 3},
+            }),
+            status
+        );
+        // listing the keys the second time was answered from the cache
+        assert_eq!(
+            vec![
+                Request::ListIdentities.to_frame(),
+                extension(STATUS_EXTENSION)
+            ],
+            *requests.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_status_from_backend() {
+        let status = Response::Extension {
+            contents: b"\x00\x00\x00\x02{}".to_vec(),
+        };
+        let backend = MockBackend::new("pipe").reply(status.clone());
+        let mut layer = ExtensionLayer::new(Box::new(backend), Box::new(FakeClock::new()), None);
+
+        assert_eq!(
+            status.to_frame(),
+            layer.request(&extension(STATUS_EXTENSION)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_relay_other_requests() {
+        let backend = MockBackend::new("mock").reply(Response::Success);
+        let requests = backend.requests();
+        let mut layer = ExtensionLayer::new(Box::new(backend), Box::new(FakeClock::new()), None);
+
+        assert_eq!(
+            Response::Success.to_frame(),
+            layer.request(&extension("other@example.com")).unwrap()
+        );
+        assert_eq!(
+            vec![extension("other@example.com")],
+            *requests.lock().unwrap()
+        );
+        assert!(layer.status().cache.is_none());
+    }
+}
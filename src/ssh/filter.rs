@@ -0,0 +1,303 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::certificate::certified_key;
+use crate::ssh::comments::Comments;
+use crate::ssh::key;
+use crate::ssh::protocol::{Identity, Request, Response};
+use anyhow::{bail, Result};
+use glob::Pattern;
+use std::str::FromStr;
+
+// matches identities by one of their properties
+#[derive(Debug, Clone)]
+pub enum KeyRule {
+    // SHA256:<base64>, as shown by ssh-add -l
+    Fingerprint(String),
+    // type:<key type>, e.g. type:ssh-ed25519
+    KeyType(String),
+    // comment:<glob>, e.g. comment:cardno:*
+    Comment(Pattern),
+}
+
+impl KeyRule {
+    // a certificate also matches the rules of the key it certifies, the key makes its signatures
+    pub fn matches(&self, identity: &Identity) -> bool {
+        self.matches_key(&identity.key_blob, &identity.comment)
+            || certified_key(&identity.key_blob)
+                .is_some_and(|key_blob| self.matches_key(&key_blob, &identity.comment))
+    }
+
+    fn matches_key(&self, key_blob: &[u8], comment: &str) -> bool {
+        match self {
+            Self::Fingerprint(fingerprint) => key::fingerprint(key_blob) == *fingerprint,
+            Self::KeyType(key_type) => key::key_type(key_blob)
+                .map(|identity_key_type| identity_key_type == *key_type)
+                .unwrap_or(false),
+            Self::Comment(pattern) => pattern.matches(comment),
+        }
+    }
+}
+
+impl FromStr for KeyRule {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        if value.starts_with("SHA256:") {
+            return Ok(Self::Fingerprint(value.to_string()));
+        }
+
+        match value.split_once(':') {
+            Some(("type", key_type)) if !key_type.is_empty() => {
+                Ok(Self::KeyType(key_type.to_string()))
+            }
+            Some(("comment", pattern)) => Ok(Self::Comment(Pattern::new(pattern)?)),
+            _ => bail!("unknown key rule {value}, expected SHA256:<fingerprint>, type:<key type> or comment:<glob>"),
+        }
+    }
+}
+
+// decides which identities the relay offers to clients
+#[derive(Debug, Clone, Default)]
+pub struct IdentityFilter {
+    // if there are any, an identity has to match one of them
+    pub allow: Vec<KeyRule>,
+    // an identity matching any of these is never offered
+    pub deny: Vec<KeyRule>,
+}
+
+impl IdentityFilter {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn is_allowed(&self, identity: &Identity) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(identity));
+        allowed && !self.deny.iter().any(|rule| rule.matches(identity))
+    }
+}
+
+// hides filtered identities from clients and refuses to sign with them
+pub struct FilterLayer {
+    inner: Box<dyn AgentBackend>,
+    filter: IdentityFilter,
+    // comment rules need them to judge signs
+    comments: Comments,
+}
+
+impl FilterLayer {
+    pub fn new(inner: Box<dyn AgentBackend>, filter: IdentityFilter) -> Self {
+        Self {
+            inner,
+            filter,
+            comments: Comments::default(),
+        }
+    }
+
+    fn list_identities(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let reply = self.inner.request(request)?;
+        self.comments.observe(&reply);
+        let identities = match Response::from_frame(&reply) {
+            Ok(Response::IdentitiesAnswer(identities)) => identities,
+            _ => return Ok(reply),
+        };
+
+        let identities = identities
+            .into_iter()
+            .filter(|identity| {
+                let allowed = self.filter.is_allowed(identity);
+                if !allowed {
+                    log::debug!("hiding {}", key::fingerprint(&identity.key_blob));
+                }
+                allowed
+            })
+            .collect();
+
+        Ok(Response::IdentitiesAnswer(identities).to_frame())
+    }
+
+    fn is_allowed(&mut self, key_blob: &[u8]) -> Result<bool> {
+        let identity = self.comments.identity(self.inner.as_mut(), key_blob)?;
+        Ok(self.filter.is_allowed(&identity))
+    }
+}
+
+impl AgentBackend for FilterLayer {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        match Request::peek_frame(request) {
+            Ok(Request::ListIdentities) => self.list_identities(request),
+            Ok(Request::SignRequest(sign_request)) => {
+                if self.is_allowed(&sign_request.key_blob)? {
+                    self.inner.request(request)
+                } else {
+                    log::warn!(
+                        "refusing to sign with filtered key {}",
+                        key::fingerprint(&sign_request.key_blob)
+                    );
+                    Ok(Response::Failure.to_frame())
+                }
+            }
+            _ => self.inner.request(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::certificate::fake::{certify, VALID_BEFORE};
+    use crate::ssh::destination::fake::{host_key, host_key_blob};
+    use crate::ssh::protocol::{SignRequest, Writer};
+
+    fn identity(key_type: &str, key: &[u8], comment: &str) -> Identity {
+        let mut writer = Writer::new();
+        writer.put_string(key_type.as_bytes());
+        writer.put_string(key);
+
+        Identity {
+            key_blob: writer.into_inner(),
+            comment: comment.to_string(),
+        }
+    }
+
+    fn identities() -> Vec<Identity> {
+        vec![
+            identity("ssh-ed25519", b"card", "cardno:000612345678"),
+            identity("ssh-rsa", b"software", "me@laptop"),
+            identity("ssh-ed25519", b"work", "me@work"),
+        ]
+    }
+
+    fn filter(allow: &[&str], deny: &[&str]) -> IdentityFilter {
+        IdentityFilter {
+            allow: allow.iter().map(|rule| rule.parse().unwrap()).collect(),
+            deny: deny.iter().map(|rule| rule.parse().unwrap()).collect(),
+        }
+    }
+
+    fn sign_request(identity: &Identity) -> Vec<u8> {
+        Request::SignRequest(SignRequest {
+            key_blob: identity.key_blob.clone(),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .to_frame()
+    }
+
+    #[test]
+    fn test_parse_key_rule() {
+        assert!(matches!(
+            "SHA256:Tx+VWQfblB/41P6SakpsP4zcmuNnVeGzxIZju4HvN5o".parse(),
+            Ok(KeyRule::Fingerprint(_))
+        ));
+        assert!(matches!("type:ssh-rsa".parse(), Ok(KeyRule::KeyType(_))));
+        assert!(matches!(
+            "comment:cardno:*".parse(),
+            Ok(KeyRule::Comment(_))
+        ));
+
+        assert!("type:".parse::<KeyRule>().is_err());
+        assert!("comment:[".parse::<KeyRule>().is_err());
+        assert!("md5:00:11".parse::<KeyRule>().is_err());
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let [card, software, work] = identities().try_into().unwrap();
+
+        // no rules allow everything
+        let everything = filter(&[], &[]);
+        assert!(everything.is_empty());
+        assert!(everything.is_allowed(&card));
+
+        let cards_only = filter(&["comment:cardno:*"], &[]);
+        assert!(cards_only.is_allowed(&card));
+        assert!(!cards_only.is_allowed(&software));
+
+        let no_rsa = filter(&[], &["type:ssh-rsa"]);
+        assert!(no_rsa.is_allowed(&card));
+        assert!(!no_rsa.is_allowed(&software));
+
+        // deny rules win over allow rules
+        let fingerprint = key::fingerprint(&work.key_blob);
+        let ed25519_but_work = filter(&["type:ssh-ed25519"], &[&fingerprint]);
+        assert!(ed25519_but_work.is_allowed(&card));
+        assert!(!ed25519_but_work.is_allowed(&work));
+        assert!(!ed25519_but_work.is_allowed(&software));
+    }
+
+    #[test]
+    fn test_certificate_matches_rules_of_its_key() {
+        let key = host_key(1);
+        let certificate = Identity {
+            key_blob: certify(&key, VALID_BEFORE).to_bytes().unwrap(),
+            comment: "me@laptop".to_string(),
+        };
+        let fingerprint: KeyRule = key::fingerprint(&host_key_blob(&key)).parse().unwrap();
+        let key_type: KeyRule = "type:ssh-ed25519".parse().unwrap();
+        let cert_type: KeyRule = "type:ssh-ed25519-cert-v01@openssh.com".parse().unwrap();
+
+        assert!(fingerprint.matches(&certificate));
+        assert!(key_type.matches(&certificate));
+        assert!(cert_type.matches(&certificate));
+        assert!(!filter(&[], &[&key::fingerprint(&host_key_blob(&key))]).is_allowed(&certificate));
+    }
+
+    #[test]
+    fn test_filter_identities_answer() {
+        let backend = MockBackend::new("mock").reply(Response::IdentitiesAnswer(identities()));
+        let mut layer = FilterLayer::new(Box::new(backend), filter(&["type:ssh-ed25519"], &[]));
+
+        let reply = layer.request(&Request::ListIdentities.to_frame()).unwrap();
+        let [card, _, work] = identities().try_into().unwrap();
+        assert_eq!(
+            Response::IdentitiesAnswer(vec![card, work]),
+            Response::from_frame(&reply).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_refuse_sign_with_filtered_key() {
+        let backend = MockBackend::new("mock").reply(Response::IdentitiesAnswer(identities()));
+        let requests = backend.requests();
+        let mut layer = FilterLayer::new(Box::new(backend), filter(&[], &["comment:me@*"]));
+        let [_, software, _] = identities().try_into().unwrap();
+
+        layer.request(&Request::ListIdentities.to_frame()).unwrap();
+        let reply = layer.request(&sign_request(&software)).unwrap();
+        assert_eq!(Response::Failure.to_frame(), reply);
+
+        // the sign request never reached the backend
+        assert_eq!(1, requests.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_sign_with_allowed_key_without_listing() {
+        let signature = Response::Signature {
+            signature: b"signature".to_vec(),
+        };
+        let backend = MockBackend::new("mock")
+            .reply(Response::IdentitiesAnswer(identities()))
+            .reply(signature.clone());
+        let mut layer = FilterLayer::new(Box::new(backend), filter(&["comment:cardno:*"], &[]));
+        let [card, _, _] = identities().try_into().unwrap();
+
+        // the comment is looked up before deciding
+        let reply = layer.request(&sign_request(&card)).unwrap();
+        assert_eq!(signature.to_frame(), reply);
+    }
+
+    #[test]
+    fn test_other_requests_pass_through() {
+        let backend = MockBackend::new("mock").reply(Response::Success);
+        let mut layer = FilterLayer::new(Box::new(backend), filter(&[], &["type:ssh-rsa"]));
+
+        let reply = layer
+            .request(&Request::RemoveAllIdentities.to_frame())
+            .unwrap();
+        assert_eq!(Response::Success.to_frame(), reply);
+    }
+}
@@ -0,0 +1,78 @@
+use crate::ssh::protocol::Reader;
+use anyhow::{bail, Result};
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+// the fingerprint OpenSSH shows for a public key blob, e.g. in ssh-add -l
+pub fn fingerprint(key_blob: &[u8]) -> String {
+    let digest = Sha256::digest(key_blob);
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(digest))
+}
+
+// every public key blob starts with its key type
+pub fn key_type(key_blob: &[u8]) -> Result<String> {
+    Reader::new(key_blob).read_utf8()
+}
+
+// the key blob of a public key as written in .pub files, `<key type> <base64> [comment]`
+pub fn parse_public_key(line: &str) -> Result<Vec<u8>> {
+    let mut parts = line.split_whitespace();
+    let (Some(line_type), Some(data)) = (parts.next(), parts.next()) else {
+        bail!("expected a key type and its base64 data");
+    };
+    let key_blob = STANDARD.decode(data)?;
+
+    let blob_type = key_type(&key_blob)?;
+    if blob_type != line_type {
+        bail!("key of type {blob_type} is labelled {line_type}");
+    }
+
+    Ok(key_blob)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // the base64 part of an ed25519 public key file
+    const ED25519_KEY: &str =
+        "AAAAC3NzaC1lZDI1NTE5AAAAINpyEDTfhUL0zO3PkDYO9Hs6RSUYBTYfi/aesKdk97sQ";
+
+    #[test]
+    fn test_fingerprint() {
+        let key_blob = STANDARD.decode(ED25519_KEY).unwrap();
+
+        // ssh-keygen -lf
+        assert_eq!(
+            "SHA256:Tx+VWQfblB/41P6SakpsP4zcmuNnVeGzxIZju4HvN5o",
+            fingerprint(&key_blob)
+        );
+    }
+
+    #[test]
+    fn test_key_type() {
+        let key_blob = STANDARD.decode(ED25519_KEY).unwrap();
+
+        assert_eq!("ssh-ed25519", key_type(&key_blob).unwrap());
+        assert!(key_type(&[0, 0, 0, 9, b's']).is_err());
+    }
+
+    #[test]
+    fn test_parse_public_key() {
+        let key_blob = STANDARD.decode(ED25519_KEY).unwrap();
+
+        assert_eq!(
+            key_blob,
+            parse_public_key(&format!("ssh-ed25519 {ED25519_KEY} me@laptop\n")).unwrap()
+        );
+        assert_eq!(
+            key_blob,
+            parse_public_key(&format!("ssh-ed25519 {ED25519_KEY}")).unwrap()
+        );
+
+        assert!(parse_public_key("ssh-ed25519").is_err());
+        assert!(parse_public_key(&format!("ssh-rsa {ED25519_KEY}")).is_err());
+        assert!(parse_public_key("ssh-ed25519 not-base64").is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use anyhow::{Context, Result};
+use glob::Pattern;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use ssh_key::known_hosts::{Entry, HostPatterns, Marker};
+use std::fs;
+use std::path::Path;
+
+// the host keys ssh has seen, to tell which host a host key belongs to
+#[derive(Debug, Clone, Default)]
+pub struct KnownHosts {
+    entries: Vec<Entry>,
+}
+
+impl KnownHosts {
+    pub fn read(path: &Path) -> Result<Self> {
+        let input = fs::read_to_string(path)
+            .with_context(|| format!("could not read known hosts from {}", path.display()))?;
+        Ok(Self::parse(&input))
+    }
+
+    // a broken line shouldn't stop us from using all the others
+    pub fn parse(input: &str) -> Self {
+        let entries = ssh_key::known_hosts::KnownHosts::new(input)
+            .enumerate()
+            .filter_map(|(index, entry)| match entry {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    log::warn!("skipping known hosts entry {}: {e}", index + 1);
+                    None
+                }
+            })
+            .collect();
+
+        Self { entries }
+    }
+
+    // whether the host key is known for a host whose name matches the pattern
+    pub fn is_known_as(&self, host_key: &[u8], host: &Pattern) -> bool {
+        self.entries_for(host_key)
+            .iter()
+            .any(|entry| matches_host(entry.host_patterns(), host))
+    }
+
+    // the names the host key is known by, except for hashed ones we can't tell
+    pub fn host_names(&self, host_key: &[u8]) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for entry in self.entries_for(host_key) {
+            if let HostPatterns::Patterns(patterns) = entry.host_patterns() {
+                for name in patterns.iter().filter(|name| !name.starts_with('!')) {
+                    let name = strip_port(name).to_string();
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+        }
+
+        names
+    }
+
+    // the plain entries for the host key, none if it was revoked
+    fn entries_for(&self, host_key: &[u8]) -> Vec<&Entry> {
+        let entries: Vec<&Entry> = self
+            .entries
+            .iter()
+            .filter(|entry| {
+                entry
+                    .public_key()
+                    .to_bytes()
+                    .is_ok_and(|entry_key| entry_key == host_key)
+            })
+            .collect();
+
+        if entries
+            .iter()
+            .any(|entry| entry.marker() == Some(&Marker::Revoked))
+        {
+            return Vec::new();
+        }
+
+        entries
+            .into_iter()
+            .filter(|entry| entry.marker().is_none())
+            .collect()
+    }
+}
+
+fn matches_host(host_patterns: &HostPatterns, host: &Pattern) -> bool {
+    match host_patterns {
+        // negated names exclude hosts from a wildcard, they don't name one
+        HostPatterns::Patterns(names) => names
+            .iter()
+            .filter(|name| !name.starts_with('!'))
+            .any(|name| host.matches(strip_port(name))),
+        // hashed names can only be compared to a host name spelled out in full
+        HostPatterns::HashedName { salt, hash } => {
+            let name = host.as_str();
+            if Pattern::escape(name) != name {
+                return false;
+            }
+
+            let Ok(mut mac) = Hmac::<Sha1>::new_from_slice(salt) else {
+                return false;
+            };
+            mac.update(name.as_bytes());
+            mac.verify_slice(hash).is_ok()
+        }
+    }
+}
+
+// [host]:port is how known_hosts names hosts on other ports than 22
+fn strip_port(name: &str) -> &str {
+    name.strip_prefix('[')
+        .and_then(|name| name.split_once("]:"))
+        .map(|(host, _port)| host)
+        .unwrap_or(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::destination::fake::{host_key, host_key_blob};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    fn pattern(host: &str) -> Pattern {
+        Pattern::new(host).unwrap()
+    }
+
+    fn hashed(salt: &[u8], host: &str) -> String {
+        let mut mac = Hmac::<Sha1>::new_from_slice(salt).unwrap();
+        mac.update(host.as_bytes());
+        format!(
+            "|1|{}|{}",
+            STANDARD.encode(salt),
+            STANDARD.encode(mac.finalize().into_bytes())
+        )
+    }
+
+    #[test]
+    fn test_is_known_as() {
+        let github = host_key(1).public_key().to_openssh().unwrap();
+        let example = host_key(2).public_key().to_openssh().unwrap();
+        let known_hosts = KnownHosts::parse(&format!(
+            "# comment\n\
+             github.com,140.82.121.4 {github}\n\
+             this line is broken\n\
+             [git.example.com]:2222,!bad.example.com {example}\n"
+        ));
+
+        let github = host_key_blob(&host_key(1));
+        assert!(known_hosts.is_known_as(&github, &pattern("github.com")));
+        assert!(known_hosts.is_known_as(&github, &pattern("*.4")));
+        assert!(!known_hosts.is_known_as(&github, &pattern("gitlab.com")));
+
+        let example = host_key_blob(&host_key(2));
+        assert!(known_hosts.is_known_as(&example, &pattern("*.example.com")));
+        assert!(!known_hosts.is_known_as(&example, &pattern("bad.example.com")));
+        assert!(!known_hosts.is_known_as(&example, &pattern("github.com")));
+
+        let unknown = host_key_blob(&host_key(3));
+        assert!(!known_hosts.is_known_as(&unknown, &pattern("*")));
+    }
+
+    #[test]
+    fn test_host_names() {
+        let github = host_key(1).public_key().to_openssh().unwrap();
+        let example = host_key(2).public_key().to_openssh().unwrap();
+        let known_hosts = KnownHosts::parse(&format!(
+            "github.com,140.82.121.4 {github}
+             {} {github}
+             [git.example.com]:2222,!bad.example.com {example}
+             @revoked old.example.com {example}
+",
+            hashed(b"salt", "hashed.example.com")
+        ));
+
+        assert_eq!(
+            vec!["github.com", "140.82.121.4"],
+            known_hosts.host_names(&host_key_blob(&host_key(1)))
+        );
+        assert!(known_hosts
+            .host_names(&host_key_blob(&host_key(2)))
+            .is_empty());
+        assert!(known_hosts
+            .host_names(&host_key_blob(&host_key(3)))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_hashed_host_names() {
+        let key = host_key(1).public_key().to_openssh().unwrap();
+        let known_hosts = KnownHosts::parse(&format!("{} {key}\n", hashed(b"salt", "github.com")));
+
+        let key = host_key_blob(&host_key(1));
+        assert!(known_hosts.is_known_as(&key, &pattern("github.com")));
+        assert!(!known_hosts.is_known_as(&key, &pattern("gitlab.com")));
+        // the name is hashed, so we can't tell if a wildcard matches it
+        assert!(!known_hosts.is_known_as(&key, &pattern("*")));
+    }
+
+    #[test]
+    fn test_revoked() {
+        let key = host_key(1).public_key().to_openssh().unwrap();
+        let known_hosts = KnownHosts::parse(&format!("github.com {key}\n@revoked * {key}\n"));
+
+        let key = host_key_blob(&host_key(1));
+        assert!(!known_hosts.is_known_as(&key, &pattern("github.com")));
+    }
+
+    #[test]
+    fn test_read_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(KnownHosts::read(&dir.path().join("known_hosts")).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::key;
+use crate::ssh::protocol::{Identity, Request, Response};
+use anyhow::{bail, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::Parser;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Print the keys the backend offers as `authorized_keys` lines, each after its fingerprint
+#[derive(Parser, Clone)]
+pub struct ListKeys {
+    /// Print the keys as a JSON array instead
+    #[clap(long, conflicts_with = "pub_dir")]
+    json: bool,
+
+    /// Write every key to a `.pub` file named after its comment in this directory, to use it
+    /// as `IdentityFile`
+    #[clap(long, value_name = "DIR")]
+    pub_dir: Option<PathBuf>,
+}
+
+// a key as written to an authorized_keys or .pub file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PublicKey {
+    pub key_type: String,
+    pub fingerprint: String,
+    pub comment: String,
+    pub public_key: String,
+}
+
+impl PublicKey {
+    pub fn from_identity(identity: &Identity) -> Result<Self> {
+        let key_type = key::key_type(&identity.key_blob)?;
+        let mut public_key = format!("{key_type} {}", STANDARD.encode(&identity.key_blob));
+        if !identity.comment.is_empty() {
+            public_key.push(' ');
+            public_key.push_str(&identity.comment);
+        }
+
+        Ok(Self {
+            key_type,
+            fingerprint: key::fingerprint(&identity.key_blob),
+            comment: identity.comment.clone(),
+            public_key,
+        })
+    }
+
+    // the comment with everything that doesn't belong in a file name replaced
+    fn file_stem(&self) -> String {
+        let stem: String = self
+            .comment
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "-_.@".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+
+        match stem.trim_start_matches('.') {
+            "" => self.key_type.clone(),
+            stem => stem.to_string(),
+        }
+    }
+}
+
+impl ListKeys {
+    pub fn run(&self, backend: &mut dyn AgentBackend, out: &mut impl Write) -> Result<()> {
+        let keys = request_keys(backend)?;
+
+        if self.json {
+            serde_json::to_writer_pretty(&mut *out, &keys)?;
+            writeln!(out)?;
+        } else if let Some(dir) = &self.pub_dir {
+            for path in write_pub_files(dir, &keys)? {
+                writeln!(out, "{}", path.display())?;
+            }
+        } else {
+            for key in &keys {
+                writeln!(out, "# {}", key.fingerprint)?;
+                writeln!(out, "{}", key.public_key)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// asks the backend for its keys, the same way ssh-add -L does
+pub fn request_keys(backend: &mut dyn AgentBackend) -> Result<Vec<PublicKey>> {
+    let reply = backend.request(&Request::ListIdentities.to_frame())?;
+    match Response::from_frame(&reply)? {
+        Response::IdentitiesAnswer(identities) => {
+            identities.iter().map(PublicKey::from_identity).collect()
+        }
+        response => bail!("{} didn't list its keys: {response}", backend.name()),
+    }
+}
+
+// keys with the same comment get a number, so none of them is overwritten
+fn write_pub_files(dir: &Path, keys: &[PublicKey]) -> Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+
+    let mut stems = HashSet::new();
+    let mut paths = Vec::new();
+    for key in keys {
+        let stem = key.file_stem();
+        let mut unique = stem.clone();
+        let mut number = 1;
+        while !stems.insert(unique.clone()) {
+            number += 1;
+            unique = format!("{stem}-{number}");
+        }
+
+        let path = dir.join(format!("{unique}.pub"));
+        fs::write(&path, format!("{}\n", key.public_key))?;
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+
+    // the base64 part of an ed25519 public key file
+    const ED25519_KEY: &str =
+        "AAAAC3NzaC1lZDI1NTE5AAAAINpyEDTfhUL0zO3PkDYO9Hs6RSUYBTYfi/aesKdk97sQ";
+    const ED25519_FINGERPRINT: &str = "SHA256:Tx+VWQfblB/41P6SakpsP4zcmuNnVeGzxIZju4HvN5o";
+
+    fn backend(comments: &[&str]) -> MockBackend {
+        let key_blob = STANDARD.decode(ED25519_KEY).unwrap();
+        let identities = comments
+            .iter()
+            .map(|comment| Identity {
+                key_blob: key_blob.clone(),
+                comment: comment.to_string(),
+            })
+            .collect();
+
+        MockBackend::new("mock").reply(Response::IdentitiesAnswer(identities))
+    }
+
+    fn list_keys(list_keys: ListKeys, mut backend: MockBackend) -> String {
+        let mut out = Vec::new();
+        list_keys.run(&mut backend, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_list_keys() {
+        let options = ListKeys {
+            json: false,
+            pub_dir: None,
+        };
+
+        assert_eq!(
+            format!(
+                "# {ED25519_FINGERPRINT}\nssh-ed25519 {ED25519_KEY} me@laptop\n\
+                 # {ED25519_FINGERPRINT}\nssh-ed25519 {ED25519_KEY}\n"
+            ),
+            list_keys(options, backend(&["me@laptop", ""]))
+        );
+    }
+
+    #[test]
+    fn test_list_keys_json() {
+        let options = ListKeys {
+            json: true,
+            pub_dir: None,
+        };
+        let keys: serde_json::Value =
+            serde_json::from_str(&list_keys(options, backend(&["me@laptop"]))).unwrap();
+
+        assert_eq!(
+            serde_json::json!([{
+                "key_type": "ssh-ed25519",
+                "fingerprint": ED25519_FINGERPRINT,
+                "comment": "me@laptop",
+                "public_key": format!("ssh-ed25519 {ED25519_KEY} me@laptop"),
+            }]),
+            keys
+        );
+    }
+
+    #[test]
+    fn test_list_keys_pub_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let pub_dir = dir.path().join("keys");
+        let options = ListKeys {
+            json: false,
+            pub_dir: Some(pub_dir.clone()),
+        };
+
+        let written = list_keys(
+            options,
+            backend(&["me@laptop", "cardno:0001", "me@laptop", "", "../up"]),
+        );
+
+        let names = [
+            "me@laptop",
+            "cardno_0001",
+            "me@laptop-2",
+            "ssh-ed25519",
+            "_up",
+        ];
+        let paths: Vec<_> = names
+            .iter()
+            .map(|name| pub_dir.join(format!("{name}.pub")))
+            .collect();
+        assert_eq!(
+            paths
+                .iter()
+                .map(|path| format!("{}\n", path.display()))
+                .collect::<String>(),
+            written
+        );
+        assert_eq!(
+            format!("ssh-ed25519 {ED25519_KEY} cardno:0001\n"),
+            fs::read_to_string(&paths[1]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_list_keys_refused() {
+        let mut backend = MockBackend::new("mock").reply(Response::Failure);
+
+        assert!(request_keys(&mut backend).is_err());
+    }
+}
@@ -0,0 +1,452 @@
+use crate::ssh::approval::{Approver, SignApproval};
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::clock::Clock;
+use crate::ssh::comments::Comments;
+use crate::ssh::key;
+use crate::ssh::protocol::{Request, Response, SignRequest};
+use anyhow::Result;
+use rand::RngCore;
+use sha2::Sha256;
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+
+const SALT_LENGTH: usize = 16;
+#[cfg(not(test))]
+const PBKDF2_ROUNDS: u32 = 100_000;
+// unoptimized test builds would spend seconds on every hash
+#[cfg(test)]
+const PBKDF2_ROUNDS: u32 = 1_000;
+
+// the passphrase a lock was set with, we only keep its salted hash
+pub struct PassphraseHash {
+    salt: [u8; SALT_LENGTH],
+    hash: [u8; 32],
+}
+
+impl PassphraseHash {
+    pub fn new(passphrase: &[u8]) -> Self {
+        let mut salt = [0u8; SALT_LENGTH];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        Self {
+            salt,
+            hash: Self::hash(passphrase, &salt),
+        }
+    }
+
+    pub fn matches(&self, passphrase: &[u8]) -> bool {
+        Self::hash(passphrase, &self.salt).ct_eq(&self.hash).into()
+    }
+
+    fn hash(passphrase: &[u8], salt: &[u8]) -> [u8; 32] {
+        let mut hash = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase, salt, PBKDF2_ROUNDS, &mut hash);
+        hash
+    }
+}
+
+// locks signing when no signature was made for a while
+pub struct IdleLock {
+    timeout: Duration,
+    clock: Box<dyn Clock>,
+    // approving a sign lifts the lock, anything on the socket could send an unlock request
+    unlocker: Box<dyn Approver>,
+    last_signed: Instant,
+    locked: bool,
+}
+
+impl IdleLock {
+    pub fn new(timeout: Duration, clock: Box<dyn Clock>, unlocker: Box<dyn Approver>) -> Self {
+        Self {
+            timeout,
+            last_signed: clock.now(),
+            clock,
+            unlocker,
+            locked: false,
+        }
+    }
+
+    pub fn is_locked(&mut self) -> bool {
+        let idle = self.clock.now().saturating_duration_since(self.last_signed);
+        if !self.locked && idle >= self.timeout {
+            self.locked = true;
+            log::info!(
+                "signing locked after {} seconds without a signature",
+                idle.as_secs()
+            );
+        }
+
+        self.locked
+    }
+
+    fn signed(&mut self) {
+        self.last_signed = self.clock.now();
+    }
+
+    fn unlock(&mut self) {
+        if self.locked {
+            log::info!("signing unlocked");
+        }
+        self.locked = false;
+        self.last_signed = self.clock.now();
+    }
+
+    // asks the unlocker to lift the lock for this sign
+    fn confirm(&mut self, approval: &SignApproval) -> bool {
+        match self.unlocker.approve(approval) {
+            Ok(true) => {
+                self.unlock();
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                log::error!("could not ask to unlock signing: {e}");
+                false
+            }
+        }
+    }
+}
+
+// locks the agent for ssh-add -x, pageant has no lock of its own
+pub struct LockLayer {
+    inner: Box<dyn AgentBackend>,
+    lock: Option<PassphraseHash>,
+    idle: Option<IdleLock>,
+    // to tell the unlocker which key is used
+    comments: Comments,
+}
+
+impl LockLayer {
+    pub fn new(inner: Box<dyn AgentBackend>, idle: Option<IdleLock>) -> Self {
+        Self {
+            inner,
+            lock: None,
+            idle,
+            comments: Comments::default(),
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.lock.is_some()
+    }
+
+    fn lock(&mut self, passphrase: &[u8]) -> Response {
+        if self.is_locked() {
+            log::warn!("refusing to lock the agent, it's locked already");
+            return Response::Failure;
+        }
+
+        self.lock = Some(PassphraseHash::new(passphrase));
+        log::info!("agent locked");
+        Response::Success
+    }
+
+    fn unlock(&mut self, passphrase: &[u8]) -> Response {
+        match &self.lock {
+            Some(lock) if lock.matches(passphrase) => {
+                self.lock = None;
+                log::info!("agent unlocked");
+                if let Some(idle) = &mut self.idle {
+                    idle.unlock();
+                }
+                Response::Success
+            }
+            Some(_) => {
+                log::warn!("refusing to unlock the agent, wrong passphrase");
+                Response::Failure
+            }
+            None => {
+                // the idle lock is only lifted by its unlocker
+                log::warn!("refusing to unlock the agent, it isn't locked");
+                Response::Failure
+            }
+        }
+    }
+
+    fn sign(&mut self, request: &[u8], sign_request: &SignRequest) -> Result<Vec<u8>> {
+        let Some(idle) = &mut self.idle else {
+            return self.inner.request(request);
+        };
+
+        if idle.is_locked() {
+            let approval = SignApproval {
+                fingerprint: key::fingerprint(&sign_request.key_blob),
+                comment: self
+                    .comments
+                    .get(self.inner.as_mut(), &sign_request.key_blob)?,
+                destination: None,
+            };
+            if !idle.confirm(&approval) {
+                log::warn!(
+                    "refusing to sign with {}, signing is locked",
+                    approval.fingerprint
+                );
+                return Ok(Response::Failure.to_frame());
+            }
+        }
+
+        let reply = self.inner.request(request)?;
+        if let Ok(Response::Signature { .. }) = Response::from_frame(&reply) {
+            idle.signed();
+        }
+
+        Ok(reply)
+    }
+}
+
+impl AgentBackend for LockLayer {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let response = match Request::peek_frame(request) {
+            Ok(Request::Lock { passphrase }) => self.lock(&passphrase),
+            Ok(Request::Unlock { passphrase }) => self.unlock(&passphrase),
+            // like ssh-agent, a locked agent has no keys and does nothing else
+            Ok(Request::ListIdentities) if self.is_locked() => Response::IdentitiesAnswer(vec![]),
+            _ if self.is_locked() => {
+                log::warn!("refusing request, the agent is locked");
+                Response::Failure
+            }
+            Ok(Request::ListIdentities) => {
+                let reply = self.inner.request(request)?;
+                self.comments.observe(&reply);
+                return Ok(reply);
+            }
+            Ok(Request::SignRequest(sign_request)) => return self.sign(request, &sign_request),
+            _ => return self.inner.request(request),
+        };
+
+        Ok(response.to_frame())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::approval::fake::ScriptedApprover;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::clock::fake::FakeClock;
+    use crate::ssh::protocol::Identity;
+    use std::sync::{Arc, Mutex};
+    use zeroize::Zeroizing;
+
+    fn lock(passphrase: &[u8]) -> Vec<u8> {
+        Request::Lock {
+            passphrase: Zeroizing::new(passphrase.to_vec()),
+        }
+        .to_frame()
+    }
+
+    fn unlock(passphrase: &[u8]) -> Vec<u8> {
+        Request::Unlock {
+            passphrase: Zeroizing::new(passphrase.to_vec()),
+        }
+        .to_frame()
+    }
+
+    fn sign_request() -> Vec<u8> {
+        Request::SignRequest(SignRequest {
+            key_blob: b"key".to_vec(),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .to_frame()
+    }
+
+    #[test]
+    fn test_passphrase_hash() {
+        let hash = PassphraseHash::new(b"secret");
+        assert!(hash.matches(b"secret"));
+        assert!(!hash.matches(b"Secret"));
+        assert!(!hash.matches(b""));
+
+        // the same passphrase is salted differently every time
+        assert_ne!(hash.hash, PassphraseHash::new(b"secret").hash);
+    }
+
+    #[test]
+    fn test_lock_and_unlock() {
+        let identities = Response::IdentitiesAnswer(vec![Identity {
+            key_blob: b"key".to_vec(),
+            comment: "me@laptop".to_string(),
+        }]);
+        let backend =
+            MockBackend::new("mock")
+                .reply(identities.clone())
+                .reply(Response::Signature {
+                    signature: b"signature".to_vec(),
+                });
+        let requests = backend.requests();
+        let mut layer = LockLayer::new(Box::new(backend), None);
+        let success = Response::Success.to_frame();
+        let failure = Response::Failure.to_frame();
+
+        assert_eq!(failure, layer.request(&unlock(b"secret")).unwrap());
+        assert_eq!(success, layer.request(&lock(b"secret")).unwrap());
+        assert_eq!(failure, layer.request(&lock(b"other")).unwrap());
+
+        // nothing reaches the backend while locked
+        assert_eq!(
+            Response::IdentitiesAnswer(vec![]).to_frame(),
+            layer.request(&Request::ListIdentities.to_frame()).unwrap()
+        );
+        assert_eq!(failure, layer.request(&sign_request()).unwrap());
+        assert_eq!(
+            failure,
+            layer
+                .request(&Request::RemoveAllIdentities.to_frame())
+                .unwrap()
+        );
+        assert!(requests.lock().unwrap().is_empty());
+
+        assert_eq!(failure, layer.request(&unlock(b"other")).unwrap());
+        assert!(layer.is_locked());
+        assert_eq!(success, layer.request(&unlock(b"secret")).unwrap());
+
+        assert_eq!(
+            identities.to_frame(),
+            layer.request(&Request::ListIdentities.to_frame()).unwrap()
+        );
+        layer.request(&sign_request()).unwrap();
+        assert_eq!(2, requests.lock().unwrap().len());
+    }
+
+    fn signature() -> Response {
+        Response::Signature {
+            signature: b"signature".to_vec(),
+        }
+    }
+
+    // an unlocker that never approves, remembering how often it was asked
+    fn refusing_unlocker() -> (Box<dyn Approver>, Arc<Mutex<Vec<SignApproval>>>) {
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let unlocker = ScriptedApprover {
+            answers: (0..10).map(|_| Ok(false)).collect(),
+            asked: asked.clone(),
+        };
+
+        (Box::new(unlocker), asked)
+    }
+
+    #[test]
+    fn test_idle_lock() {
+        let backend = MockBackend::new("mock")
+            .reply(signature())
+            .reply(Response::Failure)
+            .reply(Response::IdentitiesAnswer(vec![Identity {
+                key_blob: b"key".to_vec(),
+                comment: "me@laptop".to_string(),
+            }]))
+            .reply(signature());
+        let requests = backend.requests();
+        let clock = FakeClock::new();
+        let (unlocker, asked) = refusing_unlocker();
+        let idle = IdleLock::new(Duration::from_secs(600), Box::new(clock.clone()), unlocker);
+        let mut layer = LockLayer::new(Box::new(backend), Some(idle));
+
+        clock.advance(Duration::from_secs(599));
+        assert_eq!(
+            signature().to_frame(),
+            layer.request(&sign_request()).unwrap()
+        );
+
+        // a failed sign isn't activity
+        clock.advance(Duration::from_secs(599));
+        layer.request(&sign_request()).unwrap();
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&sign_request()).unwrap()
+        );
+        // only the comment for the unlocker was asked for
+        assert_eq!(3, requests.lock().unwrap().len());
+        assert_eq!(1, asked.lock().unwrap().len());
+        assert_eq!(
+            Some("me@laptop".to_string()),
+            asked.lock().unwrap()[0].comment
+        );
+
+        // anything on the socket could send an unlock, it doesn't lift the lock
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&unlock(b"")).unwrap()
+        );
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&sign_request()).unwrap()
+        );
+        assert_eq!(3, requests.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_unlock_lifts_idle_lock() {
+        let backend = MockBackend::new("mock").reply(signature());
+        let clock = FakeClock::new();
+        let (unlocker, _) = refusing_unlocker();
+        let idle = IdleLock::new(Duration::from_secs(600), Box::new(clock.clone()), unlocker);
+        let mut layer = LockLayer::new(Box::new(backend), Some(idle));
+
+        // the passphrase the user locked the agent with proves they're back
+        layer.request(&lock(b"secret")).unwrap();
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(
+            Response::Success.to_frame(),
+            layer.request(&unlock(b"secret")).unwrap()
+        );
+        assert_eq!(
+            signature().to_frame(),
+            layer.request(&sign_request()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_idle_unlock_by_approval() {
+        let backend = MockBackend::new("mock")
+            .reply(Response::IdentitiesAnswer(vec![Identity {
+                key_blob: b"key".to_vec(),
+                comment: "me@laptop".to_string(),
+            }]))
+            .reply(signature())
+            .reply(signature());
+        let requests = backend.requests();
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let unlocker = ScriptedApprover {
+            answers: vec![Ok(false), Err(anyhow::anyhow!("no pinentry")), Ok(true)],
+            asked: asked.clone(),
+        };
+        let clock = FakeClock::new();
+        let idle = IdleLock::new(
+            Duration::from_secs(600),
+            Box::new(clock.clone()),
+            Box::new(unlocker),
+        );
+        let mut layer = LockLayer::new(Box::new(backend), Some(idle));
+
+        layer.request(&Request::ListIdentities.to_frame()).unwrap();
+        clock.advance(Duration::from_secs(600));
+
+        let failure = Response::Failure.to_frame();
+        assert_eq!(failure, layer.request(&sign_request()).unwrap());
+        assert_eq!(failure, layer.request(&sign_request()).unwrap());
+        // ssh-add -X doesn't get around it
+        assert_eq!(failure, layer.request(&unlock(b"")).unwrap());
+        assert_eq!(
+            signature().to_frame(),
+            layer.request(&sign_request()).unwrap()
+        );
+
+        // unlocked, signing doesn't ask again
+        assert_eq!(
+            signature().to_frame(),
+            layer.request(&sign_request()).unwrap()
+        );
+        assert_eq!(3, asked.lock().unwrap().len());
+        assert_eq!(
+            Some("me@laptop".to_string()),
+            asked.lock().unwrap()[0].comment
+        );
+        assert_eq!(3, requests.lock().unwrap().len());
+    }
+}
@@ -1,17 +1,52 @@
+use crate::ssh::backend::AgentBackend;
+#[cfg(windows)]
 use crate::ssh::file_mapping::FileMapping;
+#[cfg(windows)]
 use crate::ssh::pageant_window::PageantWindow;
-use anyhow::Result;
+use anyhow::{bail, Result};
+#[cfg(windows)]
 use std::os::raw::c_ulong;
-use std::{io, process};
-
+#[cfg(windows)]
+use std::process;
+
+pub mod allowed_signers;
+pub mod approval;
+pub mod audit;
+pub mod backend;
+pub mod cache;
+pub mod certificate;
+pub mod clock;
+pub mod comments;
+pub mod destination;
+pub mod extension;
+#[cfg(windows)]
 mod file_mapping;
+pub mod filter;
+pub mod key;
+pub mod keystore;
+pub mod known_hosts;
+pub mod list_keys;
+pub mod lock;
+pub mod order;
+#[cfg(windows)]
 mod pageant_window;
+pub mod policy;
+pub mod protocol;
+pub mod rate_limit;
+pub mod server;
+pub mod session;
+pub mod sign;
+pub mod sshsig;
+pub mod verify;
 
 // https://net-ssh.github.io/ssh/v2/api/classes/Net/SSH/Authentication/Pageant.html
+#[cfg(windows)]
 const AGENT_COPY_DATA_ID: isize = 0x804e50ba;
-const AGENT_MAX_LENGTH: c_ulong = 8192;
+#[cfg_attr(not(windows), allow(dead_code))]
+const AGENT_MAX_LENGTH: u32 = 8192;
 
 // https://docs.microsoft.com/en-us/windows/win32/api/winuser/ns-winuser-copydatastruct
+#[cfg(windows)]
 #[repr(C)]
 #[derive(Debug)]
 struct CopyDataStruct {
@@ -20,170 +55,213 @@ struct CopyDataStruct {
     lp_data: isize,   // the data
 }
 
-pub struct SshPageant {}
+// one shared memory map and pageant window, reused for every request of a connection
+pub struct SshPageant {
+    #[cfg(windows)]
+    map_name: String,
+    #[cfg(windows)]
+    file_mapping: FileMapping,
+    #[cfg(windows)]
+    pageant_window: PageantWindow,
+}
 
 impl SshPageant {
-    pub fn new() -> Self {
-        Self {}
-    }
-
-    pub fn run(
-        &self,
-        pageant_window_name: &str,
-        pageant_class_name: &str,
-        stdout: &mut dyn io::Write,
-        stdin: &mut dyn io::BufRead,
-    ) -> Result<()> {
+    #[cfg(windows)]
+    pub fn new(pageant_window_name: &str, pageant_class_name: &str) -> Result<Self> {
         // build shared memory map
         let map_name = format!("WSLPageantRequest{}", process::id());
         let file_mapping = FileMapping::new(&map_name)?;
-        let shared_memory_slice = file_mapping.shared_memory();
-
-        // write our request to the shared memory
-        let request = self.read_request(stdin)?;
-        for (i, value) in request.iter().enumerate() {
-            shared_memory_slice[i] = *value;
-        }
-
-        // send message to pageant saying we've written bytes to our shared memory
         let pageant_window = PageantWindow::new(pageant_window_name, pageant_class_name)?;
-        pageant_window.send_message(&map_name)?;
 
-        // send the result to stdout
-        self.send_result(stdout, shared_memory_slice)?;
+        Ok(Self {
+            map_name,
+            file_mapping,
+            pageant_window,
+        })
+    }
 
-        Ok(())
+    #[cfg(not(windows))]
+    pub fn new(_pageant_window_name: &str, _pageant_class_name: &str) -> Result<Self> {
+        bail!("pageant is only available on windows");
     }
+}
 
-    fn read_request(&self, stdin: &mut dyn io::BufRead) -> Result<Vec<u8>> {
-        // first we need to find out how many bytes are in the request
-        // convert the first 4 bytes to a u32
-        let mut length_buffer = [0u8; 4];
-        stdin.read_exact(&mut length_buffer)?;
-        let length = u32::from_be_bytes(length_buffer);
-
-        // now we can create a buffer of the length found above
-        let mut buffer = vec![0u8; length as usize];
-        stdin.read_exact(&mut buffer)?;
-
-        // create the request vec and fill the first 4 bytes with the length
-        let mut request = vec![0u8; (length + 4) as usize];
-        for (i, value) in length_buffer.iter().enumerate() {
-            request[i] = *value;
-        }
+impl AgentBackend for SshPageant {
+    fn name(&self) -> &str {
+        "pageant"
+    }
 
-        // fill the rest of the request with the data from the buffer
-        for (i, value) in buffer.iter().enumerate() {
-            request[i + 4] = *value;
-        }
+    #[cfg(windows)]
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        // write our request to the shared memory
+        let shared_memory_slice = self.file_mapping.shared_memory();
+        write_request(shared_memory_slice, request)?;
 
-        Ok(request)
+        // send message to pageant saying we've written bytes to our shared memory
+        self.pageant_window.send_message(&self.map_name)?;
+
+        read_reply(shared_memory_slice)
     }
 
-    fn send_result(
-        &self,
-        stdout: &mut dyn io::Write,
-        shared_memory_slice: &mut [u8],
-    ) -> Result<()> {
-        // find out the length by converting the first 4 bytes to a u32
-        let length_buffer: [u8; 4] = [
-            shared_memory_slice[0],
-            shared_memory_slice[1],
-            shared_memory_slice[2],
-            shared_memory_slice[3],
-        ];
-        let length = u32::from_be_bytes(length_buffer);
+    #[cfg(not(windows))]
+    fn request(&mut self, _request: &[u8]) -> Result<Vec<u8>> {
+        bail!("pageant is only available on windows");
+    }
+}
 
-        // copy our bytes to the a vec
-        let mut result = vec![0u8; (length + 4) as usize];
-        for i in 0..(length + 4) {
-            result[i as usize] = shared_memory_slice[i as usize];
-        }
+// only the windows build talks to pageant, but the shared memory layout is tested everywhere
+#[cfg_attr(not(windows), allow(dead_code))]
+fn write_request(shared_memory_slice: &mut [u8], request: &[u8]) -> Result<()> {
+    if request.len() > shared_memory_slice.len() {
+        bail!(
+            "request of {} bytes is larger than the shared memory of {} bytes",
+            request.len(),
+            shared_memory_slice.len()
+        );
+    }
+    shared_memory_slice[..request.len()].copy_from_slice(request);
 
-        // push our bytes to stdout
-        stdout.write_all(result.as_slice())?;
-        stdout.flush()?;
+    Ok(())
+}
 
-        Ok(())
+#[cfg_attr(not(windows), allow(dead_code))]
+fn read_reply(shared_memory_slice: &[u8]) -> Result<Vec<u8>> {
+    // find out the length by converting the first 4 bytes to a u32
+    if shared_memory_slice.len() < 4 {
+        bail!("shared memory is too small to hold a reply");
+    }
+    let length_buffer: [u8; 4] = [
+        shared_memory_slice[0],
+        shared_memory_slice[1],
+        shared_memory_slice[2],
+        shared_memory_slice[3],
+    ];
+    let length = u32::from_be_bytes(length_buffer) as usize;
+
+    // pageant can't write more than the shared memory, so a larger length means the reply is corrupt
+    if length == 0 || length > shared_memory_slice.len() - 4 {
+        bail!(
+            "reply length {length} is outside of the shared memory of {} bytes",
+            shared_memory_slice.len()
+        );
     }
+
+    Ok(shared_memory_slice[..(length + 4)].to_vec())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    #[cfg(windows)]
     use crate::ssh::file_mapping::FileMapping;
-    use crate::ssh::AGENT_MAX_LENGTH;
+    #[cfg(windows)]
+    use crate::ssh::session::Session;
+    #[cfg(windows)]
     use rand::Rng;
+    #[cfg(windows)]
     use std::ffi::CStr;
+    #[cfg(windows)]
     use std::os::raw::c_char;
+    #[cfg(windows)]
     use std::process;
+    #[cfg(windows)]
     use widestring::U16CString;
+    #[cfg(windows)]
     use windows::core::PCWSTR;
+    #[cfg(windows)]
     use windows::Win32::Foundation::{HINSTANCE, HWND, LPARAM, LRESULT, WPARAM};
+    #[cfg(windows)]
     use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+    #[cfg(windows)]
     use windows::Win32::UI::WindowsAndMessaging::WM_COPYDATA;
+    #[cfg(windows)]
     use windows::Win32::UI::WindowsAndMessaging::{
         CreateWindowExW, DefWindowProcW, DestroyWindow, RegisterClassW, UnregisterClassW,
         CW_USEDEFAULT, HCURSOR, HICON, HMENU, WINDOW_EX_STYLE, WNDCLASSW, WNDCLASS_STYLES,
         WS_OVERLAPPEDWINDOW,
     };
 
+    #[cfg(windows)]
     #[test]
     fn test_run() {
         let mut stdout = Vec::new();
         let (length_bytes, data) = window_input();
 
+        // two requests pipelined back to back
+        let stdin = [&data[..12], &data[..12]].concat();
+
         let window = Window::new();
-        let ssh = SshPageant::new();
-        ssh.run(
-            &window.window_name(),
-            &window.class_name(),
-            &mut stdout,
-            &mut data.as_slice(),
-        )
-        .unwrap();
+        let ssh = SshPageant::new(&window.window_name(), &window.class_name()).unwrap();
+        Session::new(Box::new(ssh))
+            .run(&mut stdout, &mut stdin.as_slice())
+            .unwrap();
 
+        assert_eq!(24, stdout.len());
         for i in 0..(8 + 4) {
             if i < 4 {
                 assert_eq!(length_bytes[i], stdout[i]);
+                assert_eq!(length_bytes[i], stdout[i + 12]);
             } else {
                 assert_eq!(8u8, stdout[i]);
+                assert_eq!(8u8, stdout[i + 12]);
             }
         }
     }
 
     #[test]
-    fn test_read_request() {
-        let ssh = SshPageant::new();
+    fn test_write_request() {
+        let mut shared_memory = vec![0u8; AGENT_MAX_LENGTH as usize];
         let (_length_bytes, data) = window_input();
 
-        // we should only read as much of the result specified by length
-        // as we're expected 8 bytes of data + 4 from length, we want 12 bytes
-        let result = ssh.read_request(&mut data.as_slice()).unwrap();
-        assert_eq!(12, result.len() as u32);
+        write_request(&mut shared_memory, &data[..12]).unwrap();
+        assert_eq!(&data[..12], &shared_memory[..12]);
 
-        // make sure the read data is the same as the request
-        for i in 0..12_usize {
-            assert_eq!(data.get(i).unwrap(), result.get(i).unwrap());
-        }
+        // the whole shared memory can be used
+        let request = vec![1u8; AGENT_MAX_LENGTH as usize];
+        write_request(&mut shared_memory, &request).unwrap();
+
+        // but nothing more
+        let request = vec![1u8; AGENT_MAX_LENGTH as usize + 1];
+        assert!(write_request(&mut shared_memory, &request).is_err());
     }
 
     #[test]
-    fn test_send_result() {
-        let ssh = SshPageant::new();
-        let mut stdout = Vec::new();
+    fn test_read_reply() {
         let length = 6_u32.to_be_bytes();
-        let mut data = [
+        let data = [
             length[0], length[1], length[2], length[3], 0u8, 1u8, 2u8, 3u8, 4u8, 5u8, 6u8, 7u8, 8u8,
         ];
 
-        ssh.send_result(&mut stdout, &mut data).unwrap();
-        assert_eq!(10, stdout.len()); // length of 6 + 4 for u32.to_be_bytes
+        let reply = read_reply(&data).unwrap();
+        assert_eq!(10, reply.len()); // length of 6 + 4 for u32.to_be_bytes
         for (n, item) in data.iter().enumerate().take(10) {
-            assert_eq!(item, stdout.get(n).unwrap());
+            assert_eq!(item, reply.get(n).unwrap());
         }
     }
 
+    #[test]
+    fn test_read_reply_fills_shared_memory() {
+        let mut data = vec![1u8; AGENT_MAX_LENGTH as usize];
+        data[..4].copy_from_slice(&(AGENT_MAX_LENGTH - 4).to_be_bytes());
+
+        assert_eq!(data, read_reply(&data).unwrap());
+    }
+
+    #[test]
+    fn test_read_reply_corrupt_length() {
+        // the length is one byte more than the shared memory holds
+        let mut data = vec![1u8; AGENT_MAX_LENGTH as usize];
+        data[..4].copy_from_slice(&(AGENT_MAX_LENGTH - 3).to_be_bytes());
+        assert!(read_reply(&data).is_err());
+
+        // an empty reply isn't a valid agent message
+        data[..4].copy_from_slice(&0_u32.to_be_bytes());
+        assert!(read_reply(&data).is_err());
+
+        // the length itself has to fit
+        assert!(read_reply(&data[..3]).is_err());
+    }
+
     pub fn window_input() -> ([u8; 4], [u8; 13]) {
         let length: u32 = 8;
         let length_bytes = length.to_be_bytes();
@@ -210,6 +288,7 @@ mod test {
         (length_bytes, data)
     }
 
+    #[cfg(windows)]
     pub struct Window {
         window_name: U16CString,
         class_name: U16CString,
@@ -217,6 +296,7 @@ mod test {
         hwnd: HWND,
     }
 
+    #[cfg(windows)]
     impl Window {
         pub fn new() -> Self {
             let mut rng = rand::thread_rng();
@@ -289,6 +369,7 @@ mod test {
         }
     }
 
+    #[cfg(windows)]
     impl Drop for Window {
         fn drop(&mut self) {
             unsafe {
@@ -301,6 +382,7 @@ mod test {
         }
     }
 
+    #[cfg(windows)]
     extern "system" fn wnd_proc(
         param0: HWND,
         param1: u32,
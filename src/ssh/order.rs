@@ -0,0 +1,232 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::destination::{DestinationRule, SessionBinds};
+use crate::ssh::filter::KeyRule;
+use crate::ssh::known_hosts::KnownHosts;
+use crate::ssh::protocol::{Identity, Request, Response};
+use anyhow::Result;
+use glob::Pattern;
+use std::str::FromStr;
+
+// keys matching the rule are offered first, to any host or only to hosts matching one of the patterns
+#[derive(Debug, Clone)]
+pub struct OrderRule {
+    pub keys: KeyRule,
+    // empty for every host
+    pub hosts: Vec<Pattern>,
+}
+
+impl FromStr for OrderRule {
+    type Err = anyhow::Error;
+
+    // <key rule>[=<host pattern>[,<host pattern>...]]
+    fn from_str(value: &str) -> Result<Self> {
+        if value.contains('=') {
+            let DestinationRule { keys, hosts } = value.parse()?;
+            return Ok(Self { keys, hosts });
+        }
+
+        Ok(Self {
+            keys: value.parse()?,
+            hosts: vec![],
+        })
+    }
+}
+
+// reorders the identities, so ssh tries the right key before it runs out of attempts
+pub struct OrderLayer {
+    inner: Box<dyn AgentBackend>,
+    rules: Vec<OrderRule>,
+    known_hosts: KnownHosts,
+    binds: SessionBinds,
+}
+
+impl OrderLayer {
+    pub fn new(
+        inner: Box<dyn AgentBackend>,
+        rules: Vec<OrderRule>,
+        known_hosts: KnownHosts,
+    ) -> Self {
+        Self {
+            inner,
+            rules,
+            known_hosts,
+            binds: SessionBinds::default(),
+        }
+    }
+
+    // the rules for the host the connection is bound to, host rules don't apply without a bind
+    fn applicable_rules(&self) -> Vec<&KeyRule> {
+        let destination = self.binds.destination();
+
+        self.rules
+            .iter()
+            .filter(|rule| {
+                rule.hosts.is_empty()
+                    || destination.as_ref().is_some_and(|destination| {
+                        rule.hosts
+                            .iter()
+                            .any(|host| self.known_hosts.is_known_as(&destination.host_key, host))
+                    })
+            })
+            .map(|rule| &rule.keys)
+            .collect()
+    }
+
+    // keys go in the order of the first rule they match, the others follow as the backend listed them
+    fn order(&self, mut identities: Vec<Identity>) -> Vec<Identity> {
+        let rules = self.applicable_rules();
+        identities.sort_by_key(|identity| {
+            rules
+                .iter()
+                .position(|rule| rule.matches(identity))
+                .unwrap_or(rules.len())
+        });
+
+        identities
+    }
+}
+
+impl AgentBackend for OrderLayer {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        match Request::peek_frame(request) {
+            Ok(Request::ListIdentities) => {
+                let reply = self.inner.request(request)?;
+                match Response::from_frame(&reply) {
+                    Ok(Response::IdentitiesAnswer(identities)) => {
+                        Ok(Response::IdentitiesAnswer(self.order(identities)).to_frame())
+                    }
+                    _ => Ok(reply),
+                }
+            }
+            Ok(other) => {
+                self.binds.observe(&other);
+                self.inner.request(request)
+            }
+            Err(_) => self.inner.request(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::destination::fake::{host_key, session_bind};
+    use crate::ssh::policy::PolicyLayer;
+
+    fn identity(comment: &str) -> Identity {
+        Identity {
+            key_blob: comment.as_bytes().to_vec(),
+            comment: comment.to_string(),
+        }
+    }
+
+    fn comments(reply: &[u8]) -> Vec<String> {
+        match Response::from_frame(reply).unwrap() {
+            Response::IdentitiesAnswer(identities) => identities
+                .into_iter()
+                .map(|identity| identity.comment)
+                .collect(),
+            response => panic!("unexpected reply {response}"),
+        }
+    }
+
+    // a backend that accepts the session-bind and then lists the keys
+    fn order_layer(rules: &[&str]) -> OrderLayer {
+        let identities = ["cardno:0001", "cardno:0002", "me@home", "me@work"]
+            .into_iter()
+            .map(identity)
+            .collect::<Vec<_>>();
+        let backend = MockBackend::new("mock")
+            .reply(Response::Success)
+            .reply(Response::IdentitiesAnswer(identities));
+
+        let github = host_key(1).public_key().to_openssh().unwrap();
+        let known_hosts = KnownHosts::parse(&format!("github.com {github}\n"));
+        let rules = rules.iter().map(|rule| rule.parse().unwrap()).collect();
+        OrderLayer::new(Box::new(backend), rules, known_hosts)
+    }
+
+    #[test]
+    fn test_parse_order_rule() {
+        let rule: OrderRule = "comment:me@work".parse().unwrap();
+        assert!(rule.hosts.is_empty());
+
+        let rule: OrderRule = "comment:me@work=github.com,*.example.com".parse().unwrap();
+        assert_eq!(2, rule.hosts.len());
+
+        assert!("comment:me@work=".parse::<OrderRule>().is_err());
+        assert!("md5:00".parse::<OrderRule>().is_err());
+    }
+
+    #[test]
+    fn test_order_identities() {
+        let mut layer = order_layer(&["comment:me@*", "comment:cardno:0002"]);
+        layer
+            .request(&session_bind(&host_key(2), false).to_frame())
+            .unwrap();
+
+        assert_eq!(
+            vec!["me@home", "me@work", "cardno:0002", "cardno:0001"],
+            comments(&layer.request(&Request::ListIdentities.to_frame()).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_order_identities_for_host() {
+        let rules = [
+            "comment:me@work=github.com",
+            "comment:cardno:0002=*.example.com",
+            "comment:me@home",
+        ];
+
+        // github is known by its host key
+        let mut layer = order_layer(&rules);
+        layer
+            .request(&session_bind(&host_key(1), false).to_frame())
+            .unwrap();
+        assert_eq!(
+            vec!["me@work", "me@home", "cardno:0001", "cardno:0002"],
+            comments(&layer.request(&Request::ListIdentities.to_frame()).unwrap())
+        );
+
+        // an unknown host only gets the rules for every host
+        let mut layer = order_layer(&rules);
+        layer
+            .request(&session_bind(&host_key(2), false).to_frame())
+            .unwrap();
+        assert_eq!(
+            vec!["me@home", "cardno:0001", "cardno:0002", "me@work"],
+            comments(&layer.request(&Request::ListIdentities.to_frame()).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_order_identities_read_only() {
+        let backend = MockBackend::new("mock").reply(Response::IdentitiesAnswer(vec![
+            identity("me@home"),
+            identity("me@work"),
+        ]));
+        let policy = PolicyLayer::new(Box::new(backend), PolicyLayer::read_only());
+        let github = host_key(1).public_key().to_openssh().unwrap();
+        let known_hosts = KnownHosts::parse(&format!("github.com {github}\n"));
+        let rules = vec!["comment:me@work=github.com".parse().unwrap()];
+        let mut layer = OrderLayer::new(Box::new(policy), rules, known_hosts);
+
+        // the read-only policy refuses the session-bind, the order still goes by it
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer
+                .request(&session_bind(&host_key(1), false).to_frame())
+                .unwrap()
+        );
+        assert_eq!(
+            vec!["me@work", "me@home"],
+            comments(&layer.request(&Request::ListIdentities.to_frame()).unwrap())
+        );
+    }
+}
@@ -28,11 +28,12 @@ impl PageantWindow {
         // todo: test failure of finding window
         if hwnd.0 == 0 {
             log::info!("pageant window not found. launching");
-            let connect_command = Command::new("gpg-connect-agent")
-                .args(["/bye"])
-                .output()
-                .expect("foo");
-            log::info!("pageant launch status: {}", connect_command.status);
+            match Command::new("gpg-connect-agent").args(["/bye"]).output() {
+                Ok(connect_command) => {
+                    log::info!("pageant launch status: {}", connect_command.status)
+                }
+                Err(e) => log::warn!("could not launch gpg-connect-agent: {e}"),
+            }
         }
 
         unsafe {
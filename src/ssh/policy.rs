@@ -0,0 +1,312 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::protocol::{self, Request, Response};
+use anyhow::{bail, Result};
+use std::fmt;
+use std::str::FromStr;
+
+// what a request asks the agent to do, judged by its message type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestKind {
+    List,
+    Sign,
+    Add,
+    Remove,
+    RemoveAll,
+    Lock,
+    Unlock,
+    // an extension, either any of them or only the named one
+    Extension(Option<String>),
+    Unknown(u8),
+}
+
+impl RequestKind {
+    pub fn of(request: &[u8]) -> Result<Self> {
+        let message = protocol::unframe(request)?;
+        let Some(message_type) = message.first() else {
+            bail!("request is empty");
+        };
+
+        let kind = match *message_type {
+            protocol::SSH_AGENTC_REQUEST_IDENTITIES => Self::List,
+            protocol::SSH_AGENTC_SIGN_REQUEST => Self::Sign,
+            protocol::SSH_AGENTC_ADD_IDENTITY
+            | protocol::SSH_AGENTC_ADD_ID_CONSTRAINED
+            | protocol::SSH_AGENTC_ADD_SMARTCARD_KEY
+            | protocol::SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED => Self::Add,
+            protocol::SSH_AGENTC_REMOVE_IDENTITY | protocol::SSH_AGENTC_REMOVE_SMARTCARD_KEY => {
+                Self::Remove
+            }
+            protocol::SSH_AGENTC_REMOVE_ALL_IDENTITIES => Self::RemoveAll,
+            protocol::SSH_AGENTC_LOCK => Self::Lock,
+            protocol::SSH_AGENTC_UNLOCK => Self::Unlock,
+            protocol::SSH_AGENTC_EXTENSION => match Request::decode(message)? {
+                Request::Extension(extension) => Self::Extension(Some(extension.name)),
+                _ => Self::Extension(None),
+            },
+            other => Self::Unknown(other),
+        };
+
+        Ok(kind)
+    }
+
+    // whether the request may change which identities the agent offers
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            Self::List | Self::Sign => false,
+            Self::Extension(Some(name)) => ![
+                protocol::SESSION_BIND_EXTENSION,
+                protocol::QUERY_EXTENSION,
+                protocol::STATUS_EXTENSION,
+            ]
+            .contains(&name.as_str()),
+            _ => true,
+        }
+    }
+
+    // whether a request of this kind is covered by an allowed kind
+    fn allows(&self, kind: &RequestKind) -> bool {
+        match (self, kind) {
+            (Self::Extension(None), Self::Extension(_)) => true,
+            _ => self == kind,
+        }
+    }
+}
+
+impl FromStr for RequestKind {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let kind = match value {
+            "list" => Self::List,
+            "sign" => Self::Sign,
+            "add" => Self::Add,
+            "remove" => Self::Remove,
+            "remove-all" => Self::RemoveAll,
+            "lock" => Self::Lock,
+            "unlock" => Self::Unlock,
+            "extension" => Self::Extension(None),
+            _ => match value.split_once(':') {
+                Some(("extension", name)) if !name.is_empty() => {
+                    Self::Extension(Some(name.to_string()))
+                }
+                _ => bail!("unknown request kind {value}, expected list, sign, add, remove, remove-all, lock, unlock, extension or extension:<name>"),
+            },
+        };
+
+        Ok(kind)
+    }
+}
+
+impl fmt::Display for RequestKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::List => write!(f, "list"),
+            Self::Sign => write!(f, "sign"),
+            Self::Add => write!(f, "add"),
+            Self::Remove => write!(f, "remove"),
+            Self::RemoveAll => write!(f, "remove-all"),
+            Self::Lock => write!(f, "lock"),
+            Self::Unlock => write!(f, "unlock"),
+            Self::Extension(None) => write!(f, "extension"),
+            Self::Extension(Some(name)) => write!(f, "extension:{name}"),
+            Self::Unknown(message_type) => write!(f, "unknown message type {message_type}"),
+        }
+    }
+}
+
+// only lets through the kinds of requests it was told to
+pub struct PolicyLayer {
+    inner: Box<dyn AgentBackend>,
+    allowed: Vec<RequestKind>,
+}
+
+impl PolicyLayer {
+    // listing and signing is all a client needs to log in somewhere
+    pub fn read_only() -> Vec<RequestKind> {
+        vec![RequestKind::List, RequestKind::Sign]
+    }
+
+    pub fn new(inner: Box<dyn AgentBackend>, allowed: Vec<RequestKind>) -> Self {
+        Self { inner, allowed }
+    }
+
+    fn deny_reason(&self, request: &[u8]) -> Option<String> {
+        match RequestKind::of(request) {
+            Ok(kind) if self.allowed.iter().any(|allowed| allowed.allows(&kind)) => None,
+            Ok(kind) => Some(format!("{kind} requests are not allowed")),
+            Err(e) => Some(format!("request could not be classified: {e}")),
+        }
+    }
+}
+
+impl AgentBackend for PolicyLayer {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        match self.deny_reason(request) {
+            Some(reason) => {
+                log::warn!("denied request: {reason}");
+                Ok(Response::Failure.to_frame())
+            }
+            None => self.inner.request(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::protocol::{Extension, SignRequest};
+    use zeroize::Zeroizing;
+
+    fn extension(name: &str) -> Vec<u8> {
+        Request::Extension(Extension {
+            name: name.to_string(),
+            contents: vec![],
+        })
+        .to_frame()
+    }
+
+    fn sign_request() -> Vec<u8> {
+        Request::SignRequest(SignRequest {
+            key_blob: b"key".to_vec(),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .to_frame()
+    }
+
+    #[test]
+    fn test_request_kind_of() {
+        assert_eq!(
+            RequestKind::List,
+            RequestKind::of(&Request::ListIdentities.to_frame()).unwrap()
+        );
+        assert_eq!(RequestKind::Sign, RequestKind::of(&sign_request()).unwrap());
+        assert_eq!(
+            RequestKind::RemoveAll,
+            RequestKind::of(&Request::RemoveAllIdentities.to_frame()).unwrap()
+        );
+        assert_eq!(
+            RequestKind::Extension(Some("query".to_string())),
+            RequestKind::of(&extension("query")).unwrap()
+        );
+
+        // adding a key is recognized even if the key type can't be decoded
+        let add = protocol::frame(&[protocol::SSH_AGENTC_ADD_IDENTITY, 0, 0, 0, 1, b'x']);
+        assert_eq!(RequestKind::Add, RequestKind::of(&add).unwrap());
+        assert_eq!(
+            RequestKind::Unknown(99),
+            RequestKind::of(&protocol::frame(&[99])).unwrap()
+        );
+
+        assert!(RequestKind::of(&[0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_is_mutating() {
+        assert!(!RequestKind::List.is_mutating());
+        assert!(!RequestKind::Sign.is_mutating());
+        assert!(!RequestKind::Extension(Some("query".to_string())).is_mutating());
+        assert!(!RequestKind::Extension(Some("status@wsl-gpg-agent".to_string())).is_mutating());
+        assert!(RequestKind::Add.is_mutating());
+        assert!(RequestKind::Lock.is_mutating());
+        assert!(RequestKind::Extension(Some("restrict@example.com".to_string())).is_mutating());
+        assert!(RequestKind::Unknown(99).is_mutating());
+    }
+
+    #[test]
+    fn test_parse_request_kind() {
+        for kind in [
+            "list",
+            "sign",
+            "add",
+            "remove",
+            "remove-all",
+            "lock",
+            "unlock",
+            "extension",
+            "extension:session-bind@openssh.com",
+        ] {
+            assert_eq!(kind, kind.parse::<RequestKind>().unwrap().to_string());
+        }
+
+        assert!("extension:".parse::<RequestKind>().is_err());
+        assert!("everything".parse::<RequestKind>().is_err());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let backend = MockBackend::new("mock")
+            .reply(Response::IdentitiesAnswer(vec![]))
+            .reply(Response::Failure);
+        let requests = backend.requests();
+        let mut layer = PolicyLayer::new(Box::new(backend), PolicyLayer::read_only());
+
+        layer.request(&Request::ListIdentities.to_frame()).unwrap();
+        layer.request(&sign_request()).unwrap();
+
+        // none of these reach the backend
+        let denied = [
+            Request::RemoveAllIdentities.to_frame(),
+            Request::Lock {
+                passphrase: Zeroizing::new(b"secret".to_vec()),
+            }
+            .to_frame(),
+            extension("query"),
+            protocol::frame(&[99]),
+        ];
+        for request in denied {
+            assert_eq!(
+                Response::Failure.to_frame(),
+                layer.request(&request).unwrap()
+            );
+        }
+
+        assert_eq!(2, requests.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_allow_named_extension() {
+        let backend = MockBackend::new("mock").reply(Response::Success);
+        let requests = backend.requests();
+        let allowed = vec![RequestKind::Extension(Some(
+            "session-bind@openssh.com".to_string(),
+        ))];
+        let mut layer = PolicyLayer::new(Box::new(backend), allowed);
+
+        assert_eq!(
+            Response::Success.to_frame(),
+            layer
+                .request(&extension("session-bind@openssh.com"))
+                .unwrap()
+        );
+        assert_eq!(
+            Response::Failure.to_frame(),
+            layer.request(&extension("query")).unwrap()
+        );
+        assert_eq!(1, requests.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_allow_any_extension() {
+        let backend = MockBackend::new("mock")
+            .reply(Response::Success)
+            .reply(Response::Success);
+        let mut layer = PolicyLayer::new(Box::new(backend), vec![RequestKind::Extension(None)]);
+
+        assert_eq!(
+            Response::Success.to_frame(),
+            layer.request(&extension("query")).unwrap()
+        );
+        assert_eq!(
+            Response::Success.to_frame(),
+            layer
+                .request(&extension("session-bind@openssh.com"))
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,871 @@
+// https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use zeroize::Zeroizing;
+
+// the same limit OpenSSH's ssh-agent applies to a single message
+pub const MAX_MESSAGE_LENGTH: u32 = 256 * 1024;
+
+// replies from the agent
+pub const SSH_AGENT_FAILURE: u8 = 5;
+pub const SSH_AGENT_SUCCESS: u8 = 6;
+pub const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+pub const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+pub const SSH_AGENT_EXTENSION_FAILURE: u8 = 28;
+
+// requests from the client
+pub const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+pub const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+pub const SSH_AGENTC_ADD_IDENTITY: u8 = 17;
+pub const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
+pub const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
+pub const SSH_AGENTC_ADD_SMARTCARD_KEY: u8 = 20;
+pub const SSH_AGENTC_REMOVE_SMARTCARD_KEY: u8 = 21;
+pub const SSH_AGENTC_LOCK: u8 = 22;
+pub const SSH_AGENTC_UNLOCK: u8 = 23;
+pub const SSH_AGENTC_ADD_ID_CONSTRAINED: u8 = 25;
+pub const SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED: u8 = 26;
+pub const SSH_AGENTC_EXTENSION: u8 = 27;
+
+// flags for sign requests
+pub const SSH_AGENT_RSA_SHA2_256: u32 = 0x02;
+pub const SSH_AGENT_RSA_SHA2_512: u32 = 0x04;
+
+// key constraints
+pub const SSH_AGENT_CONSTRAIN_LIFETIME: u8 = 1;
+pub const SSH_AGENT_CONSTRAIN_CONFIRM: u8 = 2;
+pub const SSH_AGENT_CONSTRAIN_EXTENSION: u8 = 255;
+
+// lists the extensions an agent supports
+pub const QUERY_EXTENSION: &str = "query";
+// openssh binds agent connections to the host they were made for
+pub const SESSION_BIND_EXTENSION: &str = "session-bind@openssh.com";
+// our own, reports how the relay is doing
+pub const STATUS_EXTENSION: &str = "status@wsl-gpg-agent";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    ListIdentities,
+    SignRequest(SignRequest),
+    AddIdentity(AddIdentity),
+    RemoveIdentity { key_blob: Vec<u8> },
+    RemoveAllIdentities,
+    Lock { passphrase: Zeroizing<Vec<u8>> },
+    Unlock { passphrase: Zeroizing<Vec<u8>> },
+    Extension(Extension),
+    Unknown { message_type: u8, contents: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignRequest {
+    pub key_blob: Vec<u8>,
+    pub data: Vec<u8>,
+    pub flags: u32,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct AddIdentity {
+    pub key_type: String,
+    // the key type specific fields, still in their wire encoding
+    pub key_data: Zeroizing<Vec<u8>>,
+    pub comment: String,
+    // only ADD_ID_CONSTRAINED carries constraints
+    pub constraints: Option<Vec<Constraint>>,
+}
+
+// leaves the private key out of logs
+impl fmt::Debug for AddIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AddIdentity")
+            .field("key_type", &self.key_type)
+            .field("comment", &self.comment)
+            .field("constraints", &self.constraints)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Constraint {
+    Lifetime(u32),
+    Confirm,
+    Extension { name: String, details: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    pub name: String,
+    pub contents: Vec<u8>,
+}
+
+// the contents of a session-bind@openssh.com extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionBind {
+    pub host_key: Vec<u8>,
+    pub session_id: Vec<u8>,
+    // the host's signature over the session id, made with the host key
+    pub signature: Vec<u8>,
+    // whether the agent is forwarded to the host rather than used to log in to it
+    pub is_forwarding: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Failure,
+    Success,
+    IdentitiesAnswer(Vec<Identity>),
+    Signature { signature: Vec<u8> },
+    // SSH_AGENT_SUCCESS followed by extension specific contents
+    Extension { contents: Vec<u8> },
+    ExtensionFailure,
+    Unknown { message_type: u8, contents: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub key_blob: Vec<u8>,
+    pub comment: String,
+}
+
+impl Request {
+    pub fn from_frame(frame: &[u8]) -> Result<Self> {
+        Self::decode(unframe(frame)?)
+    }
+
+    // for the layers a request only passes through. requests adding a key aren't decoded, they're
+    // relayed as they are, so only the keystore ever copies the private key out of them
+    pub fn peek_frame(frame: &[u8]) -> Result<Self> {
+        if adds_key(frame) {
+            bail!("requests adding a key are only decoded by the keystore");
+        }
+
+        Self::from_frame(frame)
+    }
+
+    pub fn to_frame(&self) -> Vec<u8> {
+        frame(&self.encode())
+    }
+
+    pub fn decode(message: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(message);
+        let message_type = reader.read_u8()?;
+
+        let request = match message_type {
+            SSH_AGENTC_REQUEST_IDENTITIES => Self::ListIdentities,
+            SSH_AGENTC_SIGN_REQUEST => Self::SignRequest(SignRequest {
+                key_blob: reader.read_string()?.to_vec(),
+                data: reader.read_string()?.to_vec(),
+                flags: reader.read_u32()?,
+            }),
+            SSH_AGENTC_ADD_IDENTITY | SSH_AGENTC_ADD_ID_CONSTRAINED => {
+                let key_type = reader.read_utf8()?;
+                let key_data = read_private_key_fields(&key_type, &mut reader)?;
+                let comment = reader.read_utf8()?;
+                let constraints = if message_type == SSH_AGENTC_ADD_ID_CONSTRAINED {
+                    let mut constraints = Vec::new();
+                    while !reader.is_empty() {
+                        constraints.push(Constraint::decode(&mut reader)?);
+                    }
+                    Some(constraints)
+                } else {
+                    None
+                };
+
+                Self::AddIdentity(AddIdentity {
+                    key_type,
+                    key_data,
+                    comment,
+                    constraints,
+                })
+            }
+            SSH_AGENTC_REMOVE_IDENTITY => Self::RemoveIdentity {
+                key_blob: reader.read_string()?.to_vec(),
+            },
+            SSH_AGENTC_REMOVE_ALL_IDENTITIES => Self::RemoveAllIdentities,
+            SSH_AGENTC_LOCK => Self::Lock {
+                passphrase: Zeroizing::new(reader.read_string()?.to_vec()),
+            },
+            SSH_AGENTC_UNLOCK => Self::Unlock {
+                passphrase: Zeroizing::new(reader.read_string()?.to_vec()),
+            },
+            SSH_AGENTC_EXTENSION => Self::Extension(Extension {
+                name: reader.read_utf8()?,
+                contents: reader.read_rest().to_vec(),
+            }),
+            _ => Self::Unknown {
+                message_type,
+                contents: reader.read_rest().to_vec(),
+            },
+        };
+        reader.finish()?;
+
+        Ok(request)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        match self {
+            Self::ListIdentities => writer.put_u8(SSH_AGENTC_REQUEST_IDENTITIES),
+            Self::SignRequest(request) => {
+                writer.put_u8(SSH_AGENTC_SIGN_REQUEST);
+                writer.put_string(&request.key_blob);
+                writer.put_string(&request.data);
+                writer.put_u32(request.flags);
+            }
+            Self::AddIdentity(identity) => {
+                match &identity.constraints {
+                    Some(_) => writer.put_u8(SSH_AGENTC_ADD_ID_CONSTRAINED),
+                    None => writer.put_u8(SSH_AGENTC_ADD_IDENTITY),
+                }
+                writer.put_string(identity.key_type.as_bytes());
+                writer.put_raw(&identity.key_data);
+                writer.put_string(identity.comment.as_bytes());
+                for constraint in identity.constraints.iter().flatten() {
+                    constraint.encode(&mut writer);
+                }
+            }
+            Self::RemoveIdentity { key_blob } => {
+                writer.put_u8(SSH_AGENTC_REMOVE_IDENTITY);
+                writer.put_string(key_blob);
+            }
+            Self::RemoveAllIdentities => writer.put_u8(SSH_AGENTC_REMOVE_ALL_IDENTITIES),
+            Self::Lock { passphrase } => {
+                writer.put_u8(SSH_AGENTC_LOCK);
+                writer.put_string(passphrase);
+            }
+            Self::Unlock { passphrase } => {
+                writer.put_u8(SSH_AGENTC_UNLOCK);
+                writer.put_string(passphrase);
+            }
+            Self::Extension(extension) => {
+                writer.put_u8(SSH_AGENTC_EXTENSION);
+                writer.put_string(extension.name.as_bytes());
+                writer.put_raw(&extension.contents);
+            }
+            Self::Unknown {
+                message_type,
+                contents,
+            } => {
+                writer.put_u8(*message_type);
+                writer.put_raw(contents);
+            }
+        }
+
+        writer.into_inner()
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ListIdentities => write!(f, "REQUEST_IDENTITIES"),
+            Self::SignRequest(request) => write!(f, "SIGN_REQUEST(flags={})", request.flags),
+            Self::AddIdentity(identity) => match identity.constraints {
+                Some(_) => write!(f, "ADD_ID_CONSTRAINED({})", identity.key_type),
+                None => write!(f, "ADD_IDENTITY({})", identity.key_type),
+            },
+            Self::RemoveIdentity { .. } => write!(f, "REMOVE_IDENTITY"),
+            Self::RemoveAllIdentities => write!(f, "REMOVE_ALL_IDENTITIES"),
+            Self::Lock { .. } => write!(f, "LOCK"),
+            Self::Unlock { .. } => write!(f, "UNLOCK"),
+            Self::Extension(extension) => write!(f, "EXTENSION({})", extension.name),
+            Self::Unknown { message_type, .. } => write!(f, "UNKNOWN({message_type})"),
+        }
+    }
+}
+
+impl Constraint {
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        let constraint = match reader.read_u8()? {
+            SSH_AGENT_CONSTRAIN_LIFETIME => Self::Lifetime(reader.read_u32()?),
+            SSH_AGENT_CONSTRAIN_CONFIRM => Self::Confirm,
+            // the layout of the details depends on the extension, so it takes the rest of the message
+            SSH_AGENT_CONSTRAIN_EXTENSION => Self::Extension {
+                name: reader.read_utf8()?,
+                details: reader.read_rest().to_vec(),
+            },
+            other => bail!("unknown key constraint {other}"),
+        };
+
+        Ok(constraint)
+    }
+
+    fn encode(&self, writer: &mut Writer) {
+        match self {
+            Self::Lifetime(seconds) => {
+                writer.put_u8(SSH_AGENT_CONSTRAIN_LIFETIME);
+                writer.put_u32(*seconds);
+            }
+            Self::Confirm => writer.put_u8(SSH_AGENT_CONSTRAIN_CONFIRM),
+            Self::Extension { name, details } => {
+                writer.put_u8(SSH_AGENT_CONSTRAIN_EXTENSION);
+                writer.put_string(name.as_bytes());
+                writer.put_raw(details);
+            }
+        }
+    }
+}
+
+impl SessionBind {
+    pub fn decode(contents: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(contents);
+        let session_bind = Self {
+            host_key: reader.read_string()?.to_vec(),
+            session_id: reader.read_string()?.to_vec(),
+            signature: reader.read_string()?.to_vec(),
+            is_forwarding: reader.read_bool()?,
+        };
+        reader.finish()?;
+
+        Ok(session_bind)
+    }
+
+    // only ssh sends session binds, the relay encodes them in tests
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.put_string(&self.host_key);
+        writer.put_string(&self.session_id);
+        writer.put_string(&self.signature);
+        writer.put_bool(self.is_forwarding);
+
+        writer.into_inner()
+    }
+}
+
+impl Response {
+    pub fn from_frame(frame: &[u8]) -> Result<Self> {
+        Self::decode(unframe(frame)?)
+    }
+
+    pub fn to_frame(&self) -> Vec<u8> {
+        frame(&self.encode())
+    }
+
+    pub fn decode(message: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(message);
+        let message_type = reader.read_u8()?;
+
+        let response = match message_type {
+            SSH_AGENT_FAILURE => Self::Failure,
+            SSH_AGENT_SUCCESS if reader.is_empty() => Self::Success,
+            SSH_AGENT_SUCCESS => Self::Extension {
+                contents: reader.read_rest().to_vec(),
+            },
+            SSH_AGENT_IDENTITIES_ANSWER => {
+                let count = reader.read_u32()?;
+                let mut identities = Vec::new();
+                for _ in 0..count {
+                    identities.push(Identity {
+                        key_blob: reader.read_string()?.to_vec(),
+                        comment: reader.read_utf8()?,
+                    });
+                }
+                Self::IdentitiesAnswer(identities)
+            }
+            SSH_AGENT_SIGN_RESPONSE => Self::Signature {
+                signature: reader.read_string()?.to_vec(),
+            },
+            SSH_AGENT_EXTENSION_FAILURE => Self::ExtensionFailure,
+            _ => Self::Unknown {
+                message_type,
+                contents: reader.read_rest().to_vec(),
+            },
+        };
+        reader.finish()?;
+
+        Ok(response)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        match self {
+            Self::Failure => writer.put_u8(SSH_AGENT_FAILURE),
+            Self::Success => writer.put_u8(SSH_AGENT_SUCCESS),
+            Self::IdentitiesAnswer(identities) => {
+                writer.put_u8(SSH_AGENT_IDENTITIES_ANSWER);
+                writer.put_u32(identities.len() as u32);
+                for identity in identities {
+                    writer.put_string(&identity.key_blob);
+                    writer.put_string(identity.comment.as_bytes());
+                }
+            }
+            Self::Signature { signature } => {
+                writer.put_u8(SSH_AGENT_SIGN_RESPONSE);
+                writer.put_string(signature);
+            }
+            Self::Extension { contents } => {
+                writer.put_u8(SSH_AGENT_SUCCESS);
+                writer.put_raw(contents);
+            }
+            Self::ExtensionFailure => writer.put_u8(SSH_AGENT_EXTENSION_FAILURE),
+            Self::Unknown {
+                message_type,
+                contents,
+            } => {
+                writer.put_u8(*message_type);
+                writer.put_raw(contents);
+            }
+        }
+
+        writer.into_inner()
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Failure => write!(f, "FAILURE"),
+            Self::Success => write!(f, "SUCCESS"),
+            Self::IdentitiesAnswer(identities) => {
+                write!(f, "IDENTITIES_ANSWER({})", identities.len())
+            }
+            Self::Signature { .. } => write!(f, "SIGN_RESPONSE"),
+            Self::Extension { .. } => write!(f, "EXTENSION_RESPONSE"),
+            Self::ExtensionFailure => write!(f, "EXTENSION_FAILURE"),
+            Self::Unknown { message_type, .. } => write!(f, "UNKNOWN({message_type})"),
+        }
+    }
+}
+
+// prefix a message with its length as a big endian u32
+pub fn frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(message.len() + 4);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+
+    frame
+}
+
+// strip and check the length prefix of a frame
+pub fn unframe(frame: &[u8]) -> Result<&[u8]> {
+    let mut reader = Reader::new(frame);
+    let message = reader.read_string()?;
+    reader.finish()?;
+
+    Ok(message)
+}
+
+// whether the request is an ADD_IDENTITY or ADD_ID_CONSTRAINED, from its message type alone
+pub fn adds_key(frame: &[u8]) -> bool {
+    matches!(
+        unframe(frame).map(|message| message.first().copied()),
+        Ok(Some(
+            SSH_AGENTC_ADD_IDENTITY | SSH_AGENTC_ADD_ID_CONSTRAINED
+        ))
+    )
+}
+
+// the private key fields of ADD_IDENTITY aren't length prefixed as a whole,
+// so we need to know how many fields each key type has to find the comment after them
+fn read_private_key_fields(key_type: &str, reader: &mut Reader) -> Result<Zeroizing<Vec<u8>>> {
+    let fields = match key_type {
+        // n, e, d, iqmp, p, q
+        "ssh-rsa" => 6,
+        // p, q, g, y, x
+        "ssh-dss" => 5,
+        // public key, private key
+        "ssh-ed25519" => 2,
+        // curve name, public point, private scalar
+        "ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384" | "ecdsa-sha2-nistp521" => 3,
+        _ => bail!("unsupported key type {key_type}"),
+    };
+
+    let start = reader.position();
+    for _ in 0..fields {
+        reader.read_string()?;
+    }
+
+    Ok(Zeroizing::new(
+        reader.data[start..reader.position()].to_vec(),
+    ))
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| anyhow!("message is truncated"))?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let bytes = self.read_bytes(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into()?))
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_string(&mut self) -> Result<&'a [u8]> {
+        let length = self.read_u32()?;
+        self.read_bytes(length as usize)
+    }
+
+    pub fn read_utf8(&mut self) -> Result<String> {
+        Ok(std::str::from_utf8(self.read_string()?)?.to_string())
+    }
+
+    pub fn read_rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.position.min(self.data.len())..];
+        self.position = self.data.len();
+
+        rest
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        if !self.is_empty() {
+            bail!(
+                "unexpected {} trailing bytes",
+                self.data.len() - self.position
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn put_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn put_bool(&mut self, value: bool) {
+        self.put_u8(value as u8);
+    }
+
+    pub fn put_string(&mut self, value: &[u8]) {
+        self.put_u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn put_raw(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn string(value: &[u8]) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.put_string(value);
+        writer.into_inner()
+    }
+
+    fn roundtrip_request(request: Request) {
+        let encoded = request.encode();
+        assert_eq!(request, Request::decode(&encoded).unwrap());
+        assert_eq!(request, Request::from_frame(&request.to_frame()).unwrap());
+    }
+
+    fn roundtrip_response(response: Response) {
+        let encoded = response.encode();
+        assert_eq!(response, Response::decode(&encoded).unwrap());
+        assert_eq!(
+            response,
+            Response::from_frame(&response.to_frame()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_frame() {
+        assert_eq!(vec![0, 0, 0, 1, 11], frame(&[11]));
+        assert_eq!(&[11u8][..], unframe(&[0, 0, 0, 1, 11]).unwrap());
+
+        // the length has to match the frame exactly
+        assert!(unframe(&[0, 0, 0, 2, 11]).is_err());
+        assert!(unframe(&[0, 0, 0, 1, 11, 12]).is_err());
+        assert!(unframe(&[0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_decode_request_identities() {
+        assert_eq!(
+            Request::ListIdentities,
+            Request::from_frame(&[0, 0, 0, 1, 11]).unwrap()
+        );
+        roundtrip_request(Request::ListIdentities);
+    }
+
+    #[test]
+    fn test_decode_sign_request() {
+        let mut message = vec![SSH_AGENTC_SIGN_REQUEST];
+        message.extend(string(b"key"));
+        message.extend(string(b"data"));
+        message.extend(SSH_AGENT_RSA_SHA2_512.to_be_bytes());
+
+        let request = Request::decode(&message).unwrap();
+        assert_eq!(
+            Request::SignRequest(SignRequest {
+                key_blob: b"key".to_vec(),
+                data: b"data".to_vec(),
+                flags: SSH_AGENT_RSA_SHA2_512,
+            }),
+            request
+        );
+        assert_eq!(message, request.encode());
+    }
+
+    #[test]
+    fn test_decode_truncated_sign_request() {
+        let mut message = vec![SSH_AGENTC_SIGN_REQUEST];
+        message.extend(string(b"key"));
+        message.extend([0, 0, 0, 10, 1, 2]);
+
+        assert!(Request::decode(&message).is_err());
+    }
+
+    #[test]
+    fn test_decode_trailing_bytes() {
+        assert!(Request::decode(&[SSH_AGENTC_REQUEST_IDENTITIES, 0]).is_err());
+        assert!(Response::decode(&[SSH_AGENT_FAILURE, 0]).is_err());
+    }
+
+    #[test]
+    fn test_decode_empty() {
+        assert!(Request::decode(&[]).is_err());
+        assert!(Response::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn test_decode_add_identity() {
+        let mut message = vec![SSH_AGENTC_ADD_IDENTITY];
+        message.extend(string(b"ssh-ed25519"));
+        message.extend(string(&[1u8; 32]));
+        message.extend(string(&[2u8; 64]));
+        message.extend(string(b"me@example.com"));
+
+        let request = Request::decode(&message).unwrap();
+        match &request {
+            Request::AddIdentity(identity) => {
+                assert_eq!("ssh-ed25519", identity.key_type);
+                assert_eq!(4 + 32 + 4 + 64, identity.key_data.len());
+                assert_eq!("me@example.com", identity.comment);
+                assert_eq!(None, identity.constraints);
+            }
+            _ => panic!("expected ADD_IDENTITY, got {request}"),
+        }
+        assert_eq!(message, request.encode());
+    }
+
+    #[test]
+    fn test_decode_add_id_constrained() {
+        let mut message = vec![SSH_AGENTC_ADD_ID_CONSTRAINED];
+        message.extend(string(b"ecdsa-sha2-nistp256"));
+        message.extend(string(b"nistp256"));
+        message.extend(string(&[4u8; 65]));
+        message.extend(string(&[3u8; 32]));
+        message.extend(string(b"comment"));
+        message.push(SSH_AGENT_CONSTRAIN_LIFETIME);
+        message.extend(60u32.to_be_bytes());
+        message.push(SSH_AGENT_CONSTRAIN_CONFIRM);
+
+        let request = Request::decode(&message).unwrap();
+        match &request {
+            Request::AddIdentity(identity) => {
+                assert_eq!(
+                    Some(vec![Constraint::Lifetime(60), Constraint::Confirm]),
+                    identity.constraints
+                );
+            }
+            _ => panic!("expected ADD_ID_CONSTRAINED, got {request}"),
+        }
+        assert_eq!(message, request.encode());
+    }
+
+    #[test]
+    fn test_peek_frame() {
+        let mut message = vec![SSH_AGENTC_ADD_IDENTITY];
+        message.extend(string(b"ssh-ed25519"));
+        message.extend(string(&[1u8; 32]));
+        message.extend(string(&[2u8; 64]));
+        message.extend(string(b"me@example.com"));
+
+        assert!(adds_key(&frame(&message)));
+        assert!(Request::peek_frame(&frame(&message)).is_err());
+        assert!(Request::from_frame(&frame(&message)).is_ok());
+
+        assert!(!adds_key(&Request::ListIdentities.to_frame()));
+        assert!(!adds_key(&[0, 0, 0, 0]));
+        assert_eq!(
+            Request::ListIdentities,
+            Request::peek_frame(&Request::ListIdentities.to_frame()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_decode_add_identity_unknown_key_type() {
+        let mut message = vec![SSH_AGENTC_ADD_IDENTITY];
+        message.extend(string(b"ssh-unknown"));
+        message.extend(string(b"comment"));
+
+        assert!(Request::decode(&message).is_err());
+    }
+
+    #[test]
+    fn test_roundtrip_requests() {
+        roundtrip_request(Request::RemoveIdentity {
+            key_blob: b"key".to_vec(),
+        });
+        roundtrip_request(Request::RemoveAllIdentities);
+        roundtrip_request(Request::Lock {
+            passphrase: Zeroizing::new(b"secret".to_vec()),
+        });
+        roundtrip_request(Request::Unlock {
+            passphrase: Zeroizing::new(b"secret".to_vec()),
+        });
+        roundtrip_request(Request::Extension(Extension {
+            name: "query".to_string(),
+            contents: vec![],
+        }));
+        roundtrip_request(Request::Extension(Extension {
+            name: "session-bind@openssh.com".to_string(),
+            contents: vec![1, 2, 3],
+        }));
+        roundtrip_request(Request::Unknown {
+            message_type: 99,
+            contents: vec![1, 2, 3],
+        });
+        roundtrip_request(Request::AddIdentity(AddIdentity {
+            key_type: "ssh-ed25519".to_string(),
+            key_data: Zeroizing::new([string(&[1u8; 32]), string(&[2u8; 64])].concat()),
+            comment: "comment".to_string(),
+            constraints: Some(vec![Constraint::Extension {
+                name: "restrict-destination-v00@openssh.com".to_string(),
+                details: vec![0, 0, 0, 0],
+            }]),
+        }));
+    }
+
+    #[test]
+    fn test_decode_identities_answer() {
+        let mut message = vec![SSH_AGENT_IDENTITIES_ANSWER];
+        message.extend(2u32.to_be_bytes());
+        message.extend(string(b"key1"));
+        message.extend(string(b"comment1"));
+        message.extend(string(b"key2"));
+        message.extend(string(b""));
+
+        let response = Response::decode(&message).unwrap();
+        assert_eq!(
+            Response::IdentitiesAnswer(vec![
+                Identity {
+                    key_blob: b"key1".to_vec(),
+                    comment: "comment1".to_string(),
+                },
+                Identity {
+                    key_blob: b"key2".to_vec(),
+                    comment: "".to_string(),
+                },
+            ]),
+            response
+        );
+        assert_eq!(message, response.encode());
+    }
+
+    #[test]
+    fn test_decode_identities_answer_wrong_count() {
+        let mut message = vec![SSH_AGENT_IDENTITIES_ANSWER];
+        message.extend(2u32.to_be_bytes());
+        message.extend(string(b"key1"));
+        message.extend(string(b"comment1"));
+
+        assert!(Response::decode(&message).is_err());
+    }
+
+    #[test]
+    fn test_decode_session_bind() {
+        let mut contents = string(b"host key");
+        contents.extend(string(b"session id"));
+        contents.extend(string(b"signature"));
+        contents.push(1);
+
+        let session_bind = SessionBind::decode(&contents).unwrap();
+        assert_eq!(
+            SessionBind {
+                host_key: b"host key".to_vec(),
+                session_id: b"session id".to_vec(),
+                signature: b"signature".to_vec(),
+                is_forwarding: true,
+            },
+            session_bind
+        );
+        assert_eq!(contents, session_bind.encode());
+
+        assert!(SessionBind::decode(&contents[..contents.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_roundtrip_responses() {
+        roundtrip_response(Response::Failure);
+        roundtrip_response(Response::Success);
+        roundtrip_response(Response::IdentitiesAnswer(vec![]));
+        roundtrip_response(Response::Signature {
+            signature: b"signature".to_vec(),
+        });
+        roundtrip_response(Response::Extension {
+            contents: vec![0, 0, 0, 1, b'a'],
+        });
+        roundtrip_response(Response::ExtensionFailure);
+        roundtrip_response(Response::Unknown {
+            message_type: 99,
+            contents: vec![],
+        });
+
+        assert_eq!(
+            vec![0, 0, 0, 1, SSH_AGENT_FAILURE],
+            Response::Failure.to_frame()
+        );
+    }
+}
@@ -0,0 +1,460 @@
+use crate::ssh::approval::{self, COMMENT_VARIABLE, FINGERPRINT_VARIABLE};
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::clock::Clock;
+use crate::ssh::comments::Comments;
+use crate::ssh::key;
+use crate::ssh::protocol::{Request, Response};
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const LIMIT_VARIABLE: &str = "WSL_GPG_AGENT_LIMIT";
+
+// at most count signatures per period, all of them at once if they were saved up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub count: u32,
+    pub period: Duration,
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    // <count>/[<number>]<s|min|h>, e.g. 30/min or 5/10s
+    fn from_str(value: &str) -> Result<Self> {
+        let invalid = || anyhow!("unknown rate limit {value}, expected e.g. 30/min or 5/10s");
+
+        let (count, period) = value.split_once('/').ok_or_else(invalid)?;
+        let count: u32 = count.parse().map_err(|_| invalid())?;
+
+        let unit_start = period
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let (number, unit) = period.split_at(unit_start);
+        let number: u64 = match number {
+            "" => 1,
+            number => number.parse().map_err(|_| invalid())?,
+        };
+        let unit = match unit {
+            "s" => 1,
+            "min" => 60,
+            "h" => 3600,
+            _ => return Err(invalid()),
+        };
+
+        if count == 0 || number == 0 {
+            bail!("rate limit {value} would refuse every signature");
+        }
+
+        let period = number
+            .checked_mul(unit)
+            .ok_or_else(|| anyhow!("rate limit {value} has a period that is too long"))?;
+
+        Ok(Self {
+            count,
+            period: Duration::from_secs(period),
+        })
+    }
+}
+
+// a token for every signature, refilled at the rate of the limit
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.count as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        let refilled =
+            elapsed.as_secs_f64() * self.limit.count as f64 / self.limit.period.as_secs_f64();
+
+        self.tokens = (self.tokens + refilled).min(self.limit.count as f64);
+        self.updated_at = now;
+    }
+
+    fn has_token(&self) -> bool {
+        self.tokens >= 1.0
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.limit.count as f64
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+}
+
+// a limit that was hit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitAlert {
+    pub fingerprint: String,
+    pub comment: Option<String>,
+    // global or key
+    pub limit: &'static str,
+}
+
+// told once whenever signs start being refused
+pub trait Alerter {
+    fn alert(&mut self, alert: &LimitAlert);
+}
+
+// runs a command in the background for every alert, e.g. to show a notification
+pub struct CommandAlerter {
+    command: String,
+}
+
+impl CommandAlerter {
+    pub fn new(command: String) -> Self {
+        Self { command }
+    }
+}
+
+impl Alerter for CommandAlerter {
+    fn alert(&mut self, alert: &LimitAlert) {
+        let mut command = approval::shell_command(&self.command);
+        command
+            .env(FINGERPRINT_VARIABLE, &alert.fingerprint)
+            .env(
+                COMMENT_VARIABLE,
+                alert.comment.as_deref().unwrap_or_default(),
+            )
+            .env(LIMIT_VARIABLE, alert.limit)
+            // our stdout may be the client's agent connection
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+
+        // signing doesn't wait for the alert, but the command is still reaped
+        match command.spawn() {
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            }
+            Err(e) => log::error!("could not run the rate limit command: {e}"),
+        }
+    }
+}
+
+// refuses signs beyond the rate limits, overall and for every key
+pub struct RateLimitLayer {
+    inner: Box<dyn AgentBackend>,
+    clock: Box<dyn Clock>,
+    global: Option<TokenBucket>,
+    key_limit: Option<RateLimit>,
+    keys: HashMap<Vec<u8>, TokenBucket>,
+    alerter: Option<Box<dyn Alerter>>,
+    // whether we refused the last sign, so a burst raises a single alert
+    limited: bool,
+    // for the alerts
+    comments: Comments,
+}
+
+impl RateLimitLayer {
+    pub fn new(
+        inner: Box<dyn AgentBackend>,
+        clock: Box<dyn Clock>,
+        global_limit: Option<RateLimit>,
+        key_limit: Option<RateLimit>,
+        alerter: Option<Box<dyn Alerter>>,
+    ) -> Self {
+        let now = clock.now();
+
+        Self {
+            inner,
+            global: global_limit.map(|limit| TokenBucket::new(limit, now)),
+            clock,
+            key_limit,
+            keys: HashMap::new(),
+            alerter,
+            limited: false,
+            comments: Comments::default(),
+        }
+    }
+
+    // takes a token from every bucket the sign counts against, returns the limit that was hit otherwise
+    fn take_token(&mut self, key_blob: &[u8]) -> Option<&'static str> {
+        let now = self.clock.now();
+
+        if let Some(global) = &mut self.global {
+            global.refill(now);
+        }
+        let key = match self.key_limit {
+            Some(limit) => {
+                // full buckets are as good as new ones, dropping them keeps made up keys from piling up
+                self.keys.retain(|_, bucket| {
+                    bucket.refill(now);
+                    !bucket.is_full()
+                });
+                Some(
+                    self.keys
+                        .entry(key_blob.to_vec())
+                        .or_insert_with(|| TokenBucket::new(limit, now)),
+                )
+            }
+            None => None,
+        };
+
+        if self
+            .global
+            .as_ref()
+            .is_some_and(|global| !global.has_token())
+        {
+            return Some("global");
+        }
+        if key.as_ref().is_some_and(|key| !key.has_token()) {
+            return Some("key");
+        }
+
+        if let Some(global) = &mut self.global {
+            global.take();
+        }
+        if let Some(key) = key {
+            key.take();
+        }
+        None
+    }
+}
+
+impl AgentBackend for RateLimitLayer {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        match Request::peek_frame(request) {
+            Ok(Request::ListIdentities) => {
+                let reply = self.inner.request(request)?;
+                self.comments.observe(&reply);
+                Ok(reply)
+            }
+            Ok(Request::SignRequest(sign_request)) => {
+                let Some(limit) = self.take_token(&sign_request.key_blob) else {
+                    self.limited = false;
+                    return self.inner.request(request);
+                };
+
+                let fingerprint = key::fingerprint(&sign_request.key_blob);
+                log::warn!("refusing to sign with {fingerprint}, the {limit} rate limit was hit");
+                if !self.limited {
+                    if let Some(alerter) = &mut self.alerter {
+                        // the sign is refused either way, the alert just goes without a comment
+                        let comment = self
+                            .comments
+                            .get(self.inner.as_mut(), &sign_request.key_blob)
+                            .unwrap_or_else(|e| {
+                                log::warn!("could not look up the comment of the key: {e}");
+                                None
+                            });
+                        alerter.alert(&LimitAlert {
+                            fingerprint,
+                            comment,
+                            limit,
+                        });
+                    }
+                }
+                self.limited = true;
+
+                Ok(Response::Failure.to_frame())
+            }
+            _ => self.inner.request(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::clock::fake::FakeClock;
+    use crate::ssh::protocol::{Identity, SignRequest};
+    use std::sync::{Arc, Mutex};
+
+    // remembers the alerts it was given
+    struct RecordingAlerter {
+        alerts: Arc<Mutex<Vec<LimitAlert>>>,
+    }
+
+    impl Alerter for RecordingAlerter {
+        fn alert(&mut self, alert: &LimitAlert) {
+            self.alerts.lock().unwrap().push(alert.clone());
+        }
+    }
+
+    fn sign_request(key_blob: &[u8]) -> Vec<u8> {
+        Request::SignRequest(SignRequest {
+            key_blob: key_blob.to_vec(),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .to_frame()
+    }
+
+    // a backend that signs everything
+    fn signer(signatures: usize) -> MockBackend {
+        let mut backend =
+            MockBackend::new("mock").reply(Response::IdentitiesAnswer(vec![Identity {
+                key_blob: b"key".to_vec(),
+                comment: "me@laptop".to_string(),
+            }]));
+        for _ in 0..signatures {
+            backend = backend.reply(Response::Signature {
+                signature: b"signature".to_vec(),
+            });
+        }
+
+        backend
+    }
+
+    fn is_signed(reply: Vec<u8>) -> bool {
+        matches!(
+            Response::from_frame(&reply).unwrap(),
+            Response::Signature { .. }
+        )
+    }
+
+    #[test]
+    fn test_parse_rate_limit() {
+        assert_eq!(
+            RateLimit {
+                count: 30,
+                period: Duration::from_secs(60)
+            },
+            "30/min".parse().unwrap()
+        );
+        assert_eq!(
+            RateLimit {
+                count: 5,
+                period: Duration::from_secs(10)
+            },
+            "5/10s".parse().unwrap()
+        );
+        assert_eq!(
+            Duration::from_secs(7200),
+            "100/2h".parse::<RateLimit>().unwrap().period
+        );
+
+        let too_long = format!("1/{}h", u64::MAX / 3600 + 1);
+        assert!(too_long.parse::<RateLimit>().is_err());
+
+        for invalid in ["30", "30/", "30/m", "0/s", "5/0s", "-1/s", "x/min"] {
+            assert!(invalid.parse::<RateLimit>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_global_limit() {
+        let backend = signer(4);
+        let requests = backend.requests();
+        let clock = FakeClock::new();
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        let mut layer = RateLimitLayer::new(
+            Box::new(backend),
+            Box::new(clock.clone()),
+            Some("3/min".parse().unwrap()),
+            None,
+            Some(Box::new(RecordingAlerter {
+                alerts: alerts.clone(),
+            })),
+        );
+        layer.request(&Request::ListIdentities.to_frame()).unwrap();
+
+        // a burst of three, then we have to wait
+        for _ in 0..3 {
+            assert!(is_signed(layer.request(&sign_request(b"key")).unwrap()));
+        }
+        assert!(!is_signed(layer.request(&sign_request(b"key")).unwrap()));
+        assert!(!is_signed(layer.request(&sign_request(b"other")).unwrap()));
+
+        // a token comes back every 20 seconds
+        clock.advance(Duration::from_secs(19));
+        assert!(!is_signed(layer.request(&sign_request(b"key")).unwrap()));
+        clock.advance(Duration::from_secs(1));
+        assert!(is_signed(layer.request(&sign_request(b"key")).unwrap()));
+        assert!(!is_signed(layer.request(&sign_request(b"key")).unwrap()));
+
+        // refused signs never reach the backend
+        assert_eq!(5, requests.lock().unwrap().len());
+
+        // one alert for every burst
+        assert_eq!(
+            vec![
+                LimitAlert {
+                    fingerprint: key::fingerprint(b"key"),
+                    comment: Some("me@laptop".to_string()),
+                    limit: "global",
+                };
+                2
+            ],
+            *alerts.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_key_limit() {
+        let backend = signer(5);
+        let clock = FakeClock::new();
+        let mut layer = RateLimitLayer::new(
+            Box::new(backend),
+            Box::new(clock.clone()),
+            Some("4/min".parse().unwrap()),
+            Some("2/min".parse().unwrap()),
+            None,
+        );
+        layer.request(&Request::ListIdentities.to_frame()).unwrap();
+
+        assert!(is_signed(layer.request(&sign_request(b"key")).unwrap()));
+        assert!(is_signed(layer.request(&sign_request(b"key")).unwrap()));
+        assert!(!is_signed(layer.request(&sign_request(b"key")).unwrap()));
+
+        // a key refused by its own limit doesn't use up the global one
+        assert!(is_signed(layer.request(&sign_request(b"other")).unwrap()));
+        assert!(is_signed(layer.request(&sign_request(b"other")).unwrap()));
+        assert!(!is_signed(layer.request(&sign_request(b"third")).unwrap()));
+
+        clock.advance(Duration::from_secs(30));
+        assert!(is_signed(layer.request(&sign_request(b"key")).unwrap()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_alerter() {
+        let dir = tempfile::tempdir().unwrap();
+        let alerts = dir.path().join("alerts");
+        let mut alerter = CommandAlerter::new(format!(
+            r#"echo "$WSL_GPG_AGENT_FINGERPRINT|$WSL_GPG_AGENT_COMMENT|$WSL_GPG_AGENT_LIMIT" > '{}.tmp' && mv '{0}.tmp' '{0}'"#,
+            alerts.display()
+        ));
+
+        alerter.alert(&LimitAlert {
+            fingerprint: key::fingerprint(b"key"),
+            comment: None,
+            limit: "key",
+        });
+
+        // the command runs in the background
+        for _ in 0..500 {
+            if alerts.exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(
+            format!("{}||key\n", key::fingerprint(b"key")),
+            std::fs::read_to_string(alerts).unwrap()
+        );
+    }
+}
@@ -0,0 +1,377 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::session::Session;
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use zeroize::Zeroizing;
+
+// the pipe the relay listens on when no name is given
+#[cfg_attr(not(windows), allow(dead_code))]
+pub const DEFAULT_PIPE_NAME: &str = r"\\.\pipe\wsl-gpg-agent";
+
+// builds the layers that follow a single client connection on top of the shared backend
+pub type ConnectionLayers =
+    Arc<dyn Fn(Box<dyn AgentBackend>) -> Result<Box<dyn AgentBackend>> + Send + Sync>;
+
+// a request for the shared backend and where its reply goes, wiped like the session's copy
+type Call = (Zeroizing<Vec<u8>>, Sender<Result<Vec<u8>>>);
+
+// where the relay accepts connections from clients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    // a windows named pipe, or our default one
+    Pipe(Option<String>),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.split_once(':') {
+            None if value == "pipe" => Ok(Self::Pipe(None)),
+            Some(("pipe", pipe_name)) if !pipe_name.is_empty() => {
+                Ok(Self::Pipe(Some(pipe_name.to_string())))
+            }
+            Some(("unix", path)) if !path.is_empty() => Ok(Self::Unix(PathBuf::from(path))),
+            _ => bail!("unknown listen address {value}, expected pipe[:<name>] or unix:<path>"),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pipe(None) => write!(f, "pipe"),
+            Self::Pipe(Some(pipe_name)) => write!(f, "pipe:{pipe_name}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// hands requests to the thread that owns the shared backend
+struct ChannelBackend {
+    name: String,
+    calls: Sender<Call>,
+}
+
+impl AgentBackend for ChannelBackend {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let (reply_sender, reply) = mpsc::channel();
+        self.calls
+            .send((Zeroizing::new(request.to_vec()), reply_sender))
+            .map_err(|_| anyhow!("the relay is shutting down"))?;
+
+        reply
+            .recv()
+            .map_err(|_| anyhow!("the relay is shutting down"))?
+    }
+}
+
+// serves every client connection with its own session, while all of them share one backend.
+// the backend stays on this thread, pageant's window and shared memory can't move between threads
+pub fn serve(
+    address: &ListenAddress,
+    backend: &mut dyn AgentBackend,
+    layers: ConnectionLayers,
+) -> Result<()> {
+    let (calls, receiver) = mpsc::channel();
+    let name = backend.name().to_string();
+    let connect: Connect = Arc::new(move || {
+        layers(Box::new(ChannelBackend {
+            name: name.clone(),
+            calls: calls.clone(),
+        }))
+    });
+
+    match address {
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => unix::listen(path, connect)?,
+        #[cfg(not(unix))]
+        ListenAddress::Unix(_) => bail!("unix sockets are only available on unix"),
+        #[cfg(windows)]
+        ListenAddress::Pipe(pipe_name) => {
+            pipe::listen(pipe_name.as_deref().unwrap_or(DEFAULT_PIPE_NAME), connect)?
+        }
+        #[cfg(not(windows))]
+        ListenAddress::Pipe(_) => bail!("named pipes are only available on windows"),
+    }
+    log::info!("listening on {address}");
+
+    relay_calls(receiver, backend);
+    bail!("stopped listening on {address}")
+}
+
+fn relay_calls(receiver: Receiver<Call>, backend: &mut dyn AgentBackend) {
+    for (request, reply) in receiver {
+        // the client may have gone away in the meantime
+        reply.send(backend.request(&request)).ok();
+    }
+}
+
+type Connect = Arc<dyn Fn() -> Result<Box<dyn AgentBackend>> + Send + Sync>;
+
+// runs the session of one client on its own thread
+fn spawn_session<W, R>(connect: &Connect, mut writer: W, mut reader: R)
+where
+    W: io::Write + Send + 'static,
+    R: io::BufRead + Send + 'static,
+{
+    let connect = Arc::clone(connect);
+    thread::spawn(move || {
+        // the layers of a connection are built and used on its own thread only
+        let result =
+            connect().and_then(|backend| Session::new(backend).run(&mut writer, &mut reader));
+        if let Err(e) = result {
+            log::error!("ssh session failed: {e}");
+        }
+    });
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::{spawn_session, Connect};
+    use anyhow::{bail, Context, Result};
+    use std::ffi::OsString;
+    use std::fs;
+    use std::fs::DirBuilder;
+    use std::io::BufReader;
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+    use std::{process, thread};
+
+    pub fn listen(path: &Path, connect: Connect) -> Result<()> {
+        // a socket left behind by an earlier run is in the way, anything else we leave alone
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                bail!("{} exists and isn't a socket", path.display());
+            }
+            fs::remove_file(path)?;
+        }
+
+        let listener = bind_private(path)
+            .with_context(|| format!("could not listen on {}", path.display()))?;
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::error!("could not accept a connection: {e}");
+                        continue;
+                    }
+                };
+                match stream.try_clone() {
+                    Ok(reader) => spawn_session(&connect, stream, BufReader::new(reader)),
+                    Err(e) => log::error!("could not set up the connection: {e}"),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    // whoever can connect can sign with our keys, so the socket is bound in a directory only we
+    // can enter and only moved into place once nobody else can connect to it
+    fn bind_private(path: &Path) -> Result<UnixListener> {
+        let mut dir_name = OsString::from(".");
+        dir_name.push(path.file_name().unwrap_or_default());
+        dir_name.push(format!(".{}", process::id()));
+        let dir = path.with_file_name(dir_name);
+        DirBuilder::new().mode(0o700).create(&dir)?;
+
+        let private_path = dir.join("agent.sock");
+        let listener = UnixListener::bind(&private_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|listener| {
+                fs::set_permissions(&private_path, fs::Permissions::from_mode(0o600))?;
+                fs::rename(&private_path, path)?;
+                Ok(listener)
+            });
+        fs::remove_file(&private_path).ok();
+        fs::remove_dir(&dir)?;
+
+        listener
+    }
+}
+
+#[cfg(windows)]
+mod pipe {
+    use super::{spawn_session, Connect};
+    use anyhow::{Context, Result};
+    use std::io::BufReader;
+    use std::thread;
+    use tokio::net::windows::named_pipe::ServerOptions;
+    use tokio_util::io::SyncIoBridge;
+
+    pub fn listen(pipe_name: &str, connect: Connect) -> Result<()> {
+        // the sessions block on the pipes from their own threads, so the runtime needs workers
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let handle = runtime.handle().clone();
+
+        // creating the first instance fails if another relay owns the pipe already
+        let first = runtime
+            .block_on(async {
+                ServerOptions::new()
+                    .first_pipe_instance(true)
+                    .reject_remote_clients(true)
+                    .create(pipe_name)
+            })
+            .with_context(|| format!("could not listen on {pipe_name}"))?;
+
+        let pipe_name = pipe_name.to_string();
+        thread::spawn(move || {
+            runtime.block_on(async move {
+                let mut server = first;
+                loop {
+                    if let Err(e) = server.connect().await {
+                        log::error!("could not accept a connection: {e}");
+                        continue;
+                    }
+
+                    // the next client needs a new instance of the pipe to connect to
+                    let next = match ServerOptions::new()
+                        .reject_remote_clients(true)
+                        .create(&pipe_name)
+                    {
+                        Ok(next) => next,
+                        Err(e) => {
+                            log::error!("could not create another instance of {pipe_name}: {e}");
+                            return;
+                        }
+                    };
+                    let connected = std::mem::replace(&mut server, next);
+
+                    let (reader, writer) = tokio::io::split(connected);
+                    spawn_session(
+                        &connect,
+                        SyncIoBridge::new_with_handle(writer, handle.clone()),
+                        BufReader::new(SyncIoBridge::new_with_handle(reader, handle.clone())),
+                    );
+                }
+            });
+        });
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(unix)]
+    use crate::ssh::backend::mock::MockBackend;
+    #[cfg(unix)]
+    use crate::ssh::cache::CacheLayer;
+    #[cfg(unix)]
+    use crate::ssh::clock::SystemClock;
+    #[cfg(unix)]
+    use crate::ssh::protocol::{Identity, Request, Response};
+    #[cfg(unix)]
+    use std::io::{Read, Write};
+    #[cfg(unix)]
+    use std::os::unix::net::UnixStream;
+    #[cfg(unix)]
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_listen_address() {
+        for address in ["pipe", r"pipe:\\.\pipe\my-agent", "unix:/tmp/agent.sock"] {
+            assert_eq!(
+                address,
+                address.parse::<ListenAddress>().unwrap().to_string()
+            );
+        }
+
+        assert!("unix:".parse::<ListenAddress>().is_err());
+        assert!("tcp:127.0.0.1:22".parse::<ListenAddress>().is_err());
+    }
+
+    #[cfg(unix)]
+    fn list_identities(path: &std::path::Path) -> Vec<u8> {
+        let mut stream = UnixStream::connect(path).unwrap();
+        stream
+            .write_all(&Request::ListIdentities.to_frame())
+            .unwrap();
+
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length).unwrap();
+        let mut reply = vec![0u8; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut reply).unwrap();
+
+        [length.to_vec(), reply].concat()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_connections_share_the_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        let identities = Response::IdentitiesAnswer(vec![Identity {
+            key_blob: b"key".to_vec(),
+            comment: "me@laptop".to_string(),
+        }]);
+
+        let backend = MockBackend::new("mock").reply(identities.clone());
+        let requests = backend.requests();
+        let address = ListenAddress::Unix(path.clone());
+        thread::spawn(move || {
+            let mut backend = CacheLayer::new(
+                Box::new(backend),
+                Duration::from_secs(60),
+                Box::new(SystemClock),
+            );
+            serve(&address, &mut backend, Arc::new(Ok)).unwrap();
+        });
+
+        while !path.exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // the second connection is answered from the cache the first one filled
+        assert_eq!(identities.to_frame(), list_identities(&path));
+        assert_eq!(identities.to_frame(), list_identities(&path));
+        assert_eq!(1, requests.lock().unwrap().len());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_socket_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("agent.sock");
+        let address = ListenAddress::Unix(path.clone());
+        thread::spawn(move || {
+            serve(&address, &mut MockBackend::new("mock"), Arc::new(Ok)).unwrap();
+        });
+
+        while !path.exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        // nothing is left of the directory it was bound in, once it's removed right after
+        let entries = || std::fs::read_dir(dir.path()).unwrap().count();
+        for _ in 0..100 {
+            if entries() == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(1, entries());
+    }
+}
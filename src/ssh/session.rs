@@ -0,0 +1,319 @@
+use crate::ssh::backend::AgentBackend;
+use crate::ssh::protocol::{self, Response, MAX_MESSAGE_LENGTH};
+use anyhow::Result;
+use std::io;
+use zeroize::Zeroizing;
+
+// a request read from the client
+enum Incoming {
+    // may carry a private key or passphrase, so it's wiped once relayed
+    Request(Zeroizing<Vec<u8>>),
+    // the request was well framed but can't be relayed
+    Rejected(String),
+    // the client closed the connection between two requests
+    Closed,
+}
+
+// relays every request of one client connection to a backend
+pub struct Session {
+    backend: Box<dyn AgentBackend>,
+}
+
+impl Session {
+    pub fn new(backend: Box<dyn AgentBackend>) -> Self {
+        Self { backend }
+    }
+
+    pub fn run(&mut self, stdout: &mut dyn io::Write, stdin: &mut dyn io::BufRead) -> Result<()> {
+        loop {
+            let reply = match read_request(stdin)? {
+                Incoming::Request(request) => self.relay(&request),
+                Incoming::Rejected(reason) => {
+                    log::warn!("rejecting request: {reason}");
+                    Response::Failure.to_frame()
+                }
+                Incoming::Closed => {
+                    log::info!("client closed the connection");
+                    return Ok(());
+                }
+            };
+
+            // push our bytes to stdout
+            stdout.write_all(&reply)?;
+            stdout.flush()?;
+        }
+    }
+
+    fn relay(&mut self, request: &[u8]) -> Vec<u8> {
+        let reply = self.backend.request(request).and_then(|reply| {
+            // the client would lose track of the stream on a broken frame
+            protocol::unframe(&reply)?;
+            Ok(reply)
+        });
+
+        match reply {
+            Ok(reply) => reply,
+            Err(e) => {
+                log::error!("protocol error from {}: {e}", self.backend.name());
+                Response::Failure.to_frame()
+            }
+        }
+    }
+}
+
+fn read_request(stdin: &mut dyn io::BufRead) -> Result<Incoming> {
+    // nothing left to read before a new request means the client is done
+    if stdin.fill_buf()?.is_empty() {
+        return Ok(Incoming::Closed);
+    }
+
+    // first we need to find out how many bytes are in the request
+    // convert the first 4 bytes to a u32
+    let mut length_buffer = [0u8; 4];
+    stdin.read_exact(&mut length_buffer)?;
+    let length = u32::from_be_bytes(length_buffer);
+
+    // backends with less room, like pageant, reject smaller requests themselves
+    if length > MAX_MESSAGE_LENGTH {
+        // skip over the request, so the next one can still be read
+        let skipped = io::copy(
+            &mut io::Read::take(&mut *stdin, length as u64),
+            &mut io::sink(),
+        )?;
+        if skipped != length as u64 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        return Ok(Incoming::Rejected(format!(
+            "request of {length} bytes is larger than the maximum of {MAX_MESSAGE_LENGTH} bytes"
+        )));
+    }
+
+    if length == 0 {
+        return Ok(Incoming::Rejected(String::from("request is empty")));
+    }
+
+    // create the request vec with the length followed by the data
+    let mut request = Zeroizing::new(vec![0u8; (length + 4) as usize]);
+    request[..4].copy_from_slice(&length_buffer);
+    stdin.read_exact(&mut request[4..])?;
+
+    Ok(Incoming::Request(request))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::backend::mock::MockBackend;
+    use crate::ssh::test::window_input;
+
+    fn expect_request(incoming: Incoming) -> Vec<u8> {
+        match incoming {
+            Incoming::Request(request) => request.to_vec(),
+            Incoming::Rejected(reason) => panic!("request was rejected: {reason}"),
+            Incoming::Closed => panic!("connection was closed"),
+        }
+    }
+
+    #[test]
+    fn test_read_request() {
+        let (_length_bytes, data) = window_input();
+
+        // we should only read as much of the result specified by length
+        // as we're expected 8 bytes of data + 4 from length, we want 12 bytes
+        let result = expect_request(read_request(&mut data.as_slice()).unwrap());
+        assert_eq!(12, result.len() as u32);
+
+        // make sure the read data is the same as the request
+        for i in 0..12_usize {
+            assert_eq!(data.get(i).unwrap(), result.get(i).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_read_request_pipelined() {
+        let (_length_bytes, data) = window_input();
+        let data = [&data[..12], &data[..12]].concat();
+        let mut stdin = data.as_slice();
+
+        assert_eq!(
+            &data[..12],
+            expect_request(read_request(&mut stdin).unwrap())
+        );
+        assert_eq!(
+            &data[12..],
+            expect_request(read_request(&mut stdin).unwrap())
+        );
+        assert!(matches!(
+            read_request(&mut stdin).unwrap(),
+            Incoming::Closed
+        ));
+    }
+
+    #[test]
+    fn test_read_request_closed() {
+        let mut stdin: &[u8] = &[];
+        assert!(matches!(
+            read_request(&mut stdin).unwrap(),
+            Incoming::Closed
+        ));
+    }
+
+    #[test]
+    fn test_read_request_max_length() {
+        let length = MAX_MESSAGE_LENGTH;
+        let mut data = length.to_be_bytes().to_vec();
+        data.extend(vec![1u8; length as usize]);
+
+        let request = expect_request(read_request(&mut data.as_slice()).unwrap());
+        assert_eq!(MAX_MESSAGE_LENGTH as usize + 4, request.len());
+    }
+
+    #[test]
+    fn test_read_request_oversized() {
+        // one byte too many, followed by a valid request
+        let length = MAX_MESSAGE_LENGTH + 1;
+        let mut data = length.to_be_bytes().to_vec();
+        data.extend(vec![1u8; length as usize]);
+        let (_length_bytes, next) = window_input();
+        data.extend(next);
+
+        let mut stdin = data.as_slice();
+        assert!(matches!(
+            read_request(&mut stdin).unwrap(),
+            Incoming::Rejected(_)
+        ));
+
+        // the oversized request was skipped, so we can read the next one
+        assert_eq!(
+            &next[..12],
+            expect_request(read_request(&mut stdin).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_read_request_huge_length() {
+        // we must not trust the length and allocate for it
+        let data = u32::MAX.to_be_bytes();
+        assert!(read_request(&mut data.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_read_request_empty() {
+        let data = 0_u32.to_be_bytes();
+
+        assert!(matches!(
+            read_request(&mut data.as_slice()).unwrap(),
+            Incoming::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn test_read_request_truncated() {
+        let (_length_bytes, data) = window_input();
+
+        // a client disconnecting in the middle of a request is an error
+        assert!(read_request(&mut &data[..8]).is_err());
+        assert!(read_request(&mut &data[..2]).is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let backend = MockBackend::new("mock")
+            .reply(Response::Success)
+            .reply(Response::Failure);
+        let requests = backend.requests();
+
+        // two requests pipelined back to back, then the client closes the connection
+        let (_length_bytes, data) = window_input();
+        let stdin = [&data[..12], &data[..12]].concat();
+        let mut stdout = Vec::new();
+
+        let mut session = Session::new(Box::new(backend));
+        session.run(&mut stdout, &mut stdin.as_slice()).unwrap();
+
+        assert_eq!(
+            [Response::Success.to_frame(), Response::Failure.to_frame()].concat(),
+            stdout
+        );
+        assert_eq!(
+            vec![data[..12].to_vec(), data[..12].to_vec()],
+            *requests.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_run_client_disconnects_mid_request() {
+        let backend = MockBackend::new("mock").reply(Response::Success);
+        let (_length_bytes, data) = window_input();
+        let stdin = [&data[..12], &data[..6]].concat();
+        let mut stdout = Vec::new();
+
+        let mut session = Session::new(Box::new(backend));
+        assert!(session.run(&mut stdout, &mut stdin.as_slice()).is_err());
+
+        // the complete request was still answered
+        assert_eq!(Response::Success.to_frame(), stdout);
+    }
+
+    #[test]
+    fn test_run_backend_error() {
+        let backend = MockBackend::new("mock")
+            .fail("pageant is gone")
+            .reply(Response::Success);
+        let (_length_bytes, data) = window_input();
+        let stdin = [&data[..12], &data[..12]].concat();
+        let mut stdout = Vec::new();
+
+        // the failed request gets a failure, and the session carries on
+        let mut session = Session::new(Box::new(backend));
+        session.run(&mut stdout, &mut stdin.as_slice()).unwrap();
+
+        assert_eq!(
+            [Response::Failure.to_frame(), Response::Success.to_frame()].concat(),
+            stdout
+        );
+    }
+
+    #[test]
+    fn test_run_corrupt_reply() {
+        // the length of the reply doesn't match its data
+        let backend = MockBackend::new("mock")
+            .reply_frame(vec![0, 0, 0, 9, 6])
+            .reply_frame(vec![]);
+        let (_length_bytes, data) = window_input();
+        let stdin = [&data[..12], &data[..12]].concat();
+        let mut stdout = Vec::new();
+
+        let mut session = Session::new(Box::new(backend));
+        session.run(&mut stdout, &mut stdin.as_slice()).unwrap();
+
+        assert_eq!(
+            [Response::Failure.to_frame(), Response::Failure.to_frame()].concat(),
+            stdout
+        );
+    }
+
+    #[test]
+    fn test_run_oversized_request() {
+        let backend = MockBackend::new("mock").reply(Response::Success);
+        let requests = backend.requests();
+
+        let length = MAX_MESSAGE_LENGTH + 1;
+        let mut stdin = length.to_be_bytes().to_vec();
+        stdin.extend(vec![1u8; length as usize]);
+        let (_length_bytes, data) = window_input();
+        stdin.extend(&data[..12]);
+        let mut stdout = Vec::new();
+
+        // the oversized request never reaches the backend
+        let mut session = Session::new(Box::new(backend));
+        session.run(&mut stdout, &mut stdin.as_slice()).unwrap();
+
+        assert_eq!(
+            [Response::Failure.to_frame(), Response::Success.to_frame()].concat(),
+            stdout
+        );
+        assert_eq!(vec![data[..12].to_vec()], *requests.lock().unwrap());
+    }
+}
@@ -0,0 +1,264 @@
+use crate::ssh::allowed_signers::{self, AllowedSigner};
+use crate::ssh::clock::Clock;
+use crate::ssh::key;
+use crate::ssh::sshsig::SshSig;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+/// Check a signature of the message on stdin against an `allowed_signers` file like
+/// `ssh-keygen -Y verify`
+#[derive(Parser, Clone)]
+pub struct Verify {
+    /// Accepted for the arguments of `ssh-keygen`, only `verify` is supported
+    #[clap(short = 'Y', value_name = "OPERATION", value_parser = ["verify"])]
+    operation: Option<String>,
+
+    /// The principals and the keys they may sign with, in the format of ssh-keygen(1)
+    #[clap(short = 'f', value_name = "ALLOWED_SIGNERS")]
+    allowed_signers: PathBuf,
+
+    /// Who has to have made the signature
+    #[clap(short = 'I', value_name = "PRINCIPAL")]
+    principal: String,
+
+    /// What the signature has to be made for, e.g. `git` or `file`
+    #[clap(short = 'n', value_name = "NAMESPACE")]
+    namespace: String,
+
+    /// The armored signature
+    #[clap(short = 's', value_name = "SIGNATURE_FILE")]
+    signature_file: PathBuf,
+
+    /// `verify-time=<YYYYMMDD[HHMM[SS]][Z]>` checks the key was valid then instead of now
+    #[clap(short = 'O', value_name = "OPTION")]
+    options: Vec<String>,
+}
+
+impl Verify {
+    pub fn run(
+        &self,
+        clock: &dyn Clock,
+        stdin: &mut impl Read,
+        stdout: &mut impl Write,
+    ) -> Result<()> {
+        let time = self.verify_time(clock)?;
+        if self.namespace.is_empty() {
+            bail!("the namespace can't be empty");
+        }
+
+        let armored = fs::read_to_string(&self.signature_file)
+            .with_context(|| format!("could not read {}", self.signature_file.display()))?;
+        let signature = SshSig::unarmor(&armored).context("could not parse the signature")?;
+        let mut message = Vec::new();
+        stdin.read_to_end(&mut message)?;
+
+        let signers = allowed_signers::read(&self.allowed_signers)?;
+        self.verify(&signature, &message, &signers, time)?;
+
+        writeln!(
+            stdout,
+            "Good \"{}\" signature for {} with {} key {}",
+            self.namespace,
+            self.principal,
+            key_type_name(&key::key_type(&signature.public_key)?),
+            key::fingerprint(&signature.public_key)
+        )?;
+
+        Ok(())
+    }
+
+    fn verify(
+        &self,
+        signature: &SshSig,
+        message: &[u8],
+        signers: &[AllowedSigner],
+        time: u64,
+    ) -> Result<()> {
+        if signature.namespace != self.namespace {
+            bail!(
+                "the signature is for namespace {} instead of {}",
+                signature.namespace,
+                self.namespace
+            );
+        }
+        signature
+            .verify(message)
+            .context("could not verify the signature")?;
+
+        if !signers.iter().any(|signer| {
+            signer.allows(
+                &self.principal,
+                &self.namespace,
+                &signature.public_key,
+                time,
+            )
+        }) {
+            bail!(
+                "{} isn't allowed to sign for {} with {}",
+                self.principal,
+                self.namespace,
+                key::fingerprint(&signature.public_key)
+            );
+        }
+
+        Ok(())
+    }
+
+    // seconds since the epoch
+    fn verify_time(&self, clock: &dyn Clock) -> Result<u64> {
+        let mut verify_time = None;
+        for option in &self.options {
+            match option.split_once('=') {
+                Some((name, value)) if name.eq_ignore_ascii_case("verify-time") => {
+                    verify_time = Some(allowed_signers::parse_time(value)?);
+                }
+                _ => bail!("unsupported option {option}"),
+            }
+        }
+
+        match verify_time {
+            Some(verify_time) => Ok(verify_time),
+            None => Ok(clock
+                .system_time()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|_| anyhow!("the clock is before 1970"))?
+                .as_secs()),
+        }
+    }
+}
+
+// the names ssh-keygen prints for key types
+fn key_type_name(key_type: &str) -> &str {
+    match key_type {
+        "ssh-ed25519" => "ED25519",
+        "ssh-rsa" => "RSA",
+        "ssh-dss" => "DSA",
+        "sk-ssh-ed25519@openssh.com" => "ED25519-SK",
+        "sk-ecdsa-sha2-nistp256@openssh.com" => "ECDSA-SK",
+        key_type if key_type.starts_with("ecdsa-sha2-") => "ECDSA",
+        key_type => key_type,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ssh::clock::fake::FakeClock;
+    use crate::ssh::sshsig::fake::{ED25519_PUBLIC_KEY, ED25519_SIGNATURE, MESSAGE};
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    // 2024-01-01T00:00:00Z
+    const NOW: u64 = 1704067200;
+
+    fn verify(dir: &Path, allowed_signers: &str, args: &[&str], message: &[u8]) -> Result<String> {
+        let allowed_signers_path = dir.join("allowed_signers");
+        fs::write(&allowed_signers_path, allowed_signers).unwrap();
+        let signature_path = dir.join("message.sig");
+        fs::write(&signature_path, ED25519_SIGNATURE).unwrap();
+
+        let mut command = vec![
+            "verify",
+            "-f",
+            allowed_signers_path.to_str().unwrap(),
+            "-s",
+            signature_path.to_str().unwrap(),
+        ];
+        command.extend(args);
+        let options = Verify::try_parse_from(command)?;
+
+        let clock = FakeClock::at(SystemTime::UNIX_EPOCH + Duration::from_secs(NOW));
+        let mut stdin = message;
+        let mut stdout = Vec::new();
+        options.run(&clock, &mut stdin, &mut stdout)?;
+
+        Ok(String::from_utf8(stdout)?)
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let allowed_signers = format!("me@laptop {ED25519_PUBLIC_KEY}\n");
+
+        assert_eq!(
+            "Good \"git\" signature for me@laptop with ED25519 key \
+             SHA256:XpWpGb4rZGEU3do2nRUZpUHrcuBkKb/xFDpvUKtLqhQ\n",
+            verify(
+                dir.path(),
+                &allowed_signers,
+                &["-Y", "verify", "-I", "me@laptop", "-n", "git"],
+                MESSAGE
+            )
+            .unwrap()
+        );
+
+        // another principal, namespace or message
+        for (args, message) in [
+            (["-I", "you@laptop", "-n", "git"], MESSAGE),
+            (["-I", "me@laptop", "-n", "file"], MESSAGE),
+            (["-I", "me@laptop", "-n", "git"], b"hello".as_slice()),
+        ] {
+            assert!(verify(dir.path(), &allowed_signers, &args, message).is_err());
+        }
+
+        // an unknown key
+        let other_key =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAINpyEDTfhUL0zO3PkDYO9Hs6RSUYBTYfi/aesKdk97sQ";
+        assert!(verify(
+            dir.path(),
+            &format!("me@laptop {other_key}\n"),
+            &["-I", "me@laptop", "-n", "git"],
+            MESSAGE
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_verify_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = ["-I", "me@laptop", "-n", "git"];
+        let allowed = |options: &str| {
+            verify(
+                dir.path(),
+                &format!("*@laptop,!you@* {options} {ED25519_PUBLIC_KEY}\n"),
+                &args,
+                MESSAGE,
+            )
+            .is_ok()
+        };
+
+        assert!(allowed("namespaces=\"file,g*\""));
+        assert!(!allowed("namespaces=\"file\""));
+        assert!(allowed(
+            "valid-after=\"20240101Z\",valid-before=\"20240101Z\""
+        ));
+        assert!(!allowed("valid-after=\"20240101000001Z\""));
+        assert!(!allowed("valid-before=\"202312312359Z\""));
+        // signatures made with certificates aren't supported
+        assert!(!allowed("cert-authority"));
+    }
+
+    #[test]
+    fn test_verify_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let allowed_signers =
+            format!("me@laptop valid-before=\"20230101Z\" {ED25519_PUBLIC_KEY}\n");
+        let verify_at = |time: &str| {
+            verify(
+                dir.path(),
+                &allowed_signers,
+                &["-I", "me@laptop", "-n", "git", "-O", time],
+                MESSAGE,
+            )
+        };
+
+        assert!(verify_at("verify-time=20221231Z").is_ok());
+        assert!(verify_at("verify-time=20230102Z").is_err());
+        assert!(verify_at("verify-time=yesterday").is_err());
+        assert!(verify_at("print-pubkey").is_err());
+    }
+}